@@ -0,0 +1,222 @@
+use crate::{
+    boundary::Periods,
+    cellgrid::StationaryCharge,
+    lib::helpers::{K, XY},
+};
+
+// maximum number of charges stored in a leaf of the tree
+const LEAF_CAPACITY: usize = 8;
+// charges at (almost) the same position can't be separated by subdividing,
+// so the tree depth is limited
+const MAX_DEPTH: usize = 32;
+
+struct Node {
+    // the node covers the square [center - half_size, center + half_size]
+    center: XY<f64>,
+    half_size: f64,
+    // multipole expansion of the charges inside the node, around `charge_center`
+    // (the |q|-weighted mean position of the charges)
+    total_charge: f64,
+    charge_center: XY<f64>,
+    dipole: XY<f64>,
+    children: Vec<Node>,
+    // only leaves hold the charges directly
+    charges: Vec<(XY<f64>, f64)>,
+}
+
+impl Node {
+    fn new(charges: Vec<(XY<f64>, f64)>, center: XY<f64>, half_size: f64, depth: usize) -> Self {
+        let abs_charge: f64 = charges.iter().map(|(_, q)| q.abs()).sum();
+        let charge_center = if abs_charge > 0. {
+            charges
+                .iter()
+                .fold(XY::zero(), |sum, &(position, q)| sum + position * q.abs())
+                * (1. / abs_charge)
+        } else {
+            center
+        };
+        let total_charge = charges.iter().map(|(_, q)| q).sum();
+        let dipole = charges.iter().fold(XY::zero(), |sum, &(position, q)| {
+            sum + (position - charge_center) * q
+        });
+
+        let mut node = Node {
+            center,
+            half_size,
+            total_charge,
+            charge_center,
+            dipole,
+            children: Vec::new(),
+            charges: Vec::new(),
+        };
+
+        if charges.len() <= LEAF_CAPACITY || depth >= MAX_DEPTH {
+            node.charges = charges;
+            return node;
+        }
+
+        // split the charges between the four quadrants
+        let mut quadrants: [Vec<(XY<f64>, f64)>; 4] = Default::default();
+        for (position, q) in charges {
+            let index = (position.x >= center.x) as usize + 2 * (position.y >= center.y) as usize;
+            quadrants[index].push((position, q));
+        }
+        let quarter = half_size / 2.;
+        for (index, quadrant) in quadrants.into_iter().enumerate() {
+            if quadrant.is_empty() {
+                continue;
+            }
+            let child_center = XY {
+                x: center.x + if index % 2 == 1 { quarter } else { -quarter },
+                y: center.y + if index / 2 == 1 { quarter } else { -quarter },
+            };
+            node.children
+                .push(Node::new(quadrant, child_center, quarter, depth + 1));
+        }
+        node
+    }
+
+    // distance from the point to the (nearest image of the) square covered by
+    // the node (0 if inside)
+    fn distance_to(&self, point: XY<f64>, periods: Periods) -> f64 {
+        let d = periods.wrap(point - self.center);
+        let dx = (d.x.abs() - self.half_size).max(0.);
+        let dy = (d.y.abs() - self.half_size).max(0.);
+        (dx.powi(2) + dy.powi(2)).sqrt()
+    }
+
+    // Whether all of the charges of the node have their nearest images on the
+    // same side as its charge center, so the expansion can use a single image.
+    // The charges are at most 2 * half_size from the charge center.
+    fn single_image(&self, r_vec: XY<f64>, periods: Periods) -> bool {
+        let fits = |d: f64, period: Option<f64>| {
+            period.is_none_or(|period| d.abs() + 2. * self.half_size < period / 2.)
+        };
+        fits(r_vec.x, periods.x) && fits(r_vec.y, periods.y)
+    }
+
+    // adds the field of the node at the point of the query to `intensity` and
+    // `potential`, returns false if a charge is too close (see Query)
+    fn accumulate(&self, query: &Query, intensity: &mut XY<f64>, potential: &mut f64) -> bool {
+        let Query {
+            point,
+            theta,
+            min_distance,
+            softening,
+            periods,
+        } = *query;
+        if self.children.is_empty() {
+            for &(position, q) in &self.charges {
+                let r_vec = periods.wrap(point - position);
+                let r = (r_vec.length().powi(2) + softening.powi(2)).sqrt();
+                if (softening == 0. && r < min_distance) || r == 0. {
+                    return false;
+                }
+                *intensity += r_vec * (K * q / r.powi(3));
+                *potential += K * q / r;
+            }
+            return true;
+        }
+
+        let r_vec = periods.wrap(point - self.charge_center);
+        let r = r_vec.length();
+        // the node is far enough to be replaced by its multipole expansion,
+        // nodes that could contain a charge closer than min_distance are always opened
+        if r > 0.
+            && 2. * self.half_size < theta * r
+            && self.distance_to(point, periods) > min_distance.max(softening)
+            && self.single_image(r_vec, periods)
+        {
+            let r_hat = r_vec * (1. / r);
+            let p_dot_r = self.dipole.x * r_hat.x + self.dipole.y * r_hat.y;
+            // monopole
+            *intensity += r_vec * (K * self.total_charge / r.powi(3));
+            *potential += K * self.total_charge / r;
+            // dipole
+            *intensity += (r_hat * (3. * p_dot_r) - self.dipole) * (K / r.powi(3));
+            *potential += K * p_dot_r / r.powi(2);
+            return true;
+        }
+
+        self.children
+            .iter()
+            .all(|child| child.accumulate(query, intensity, potential))
+    }
+}
+
+// A field evaluation: the charges closer to `point` than `min_distance` (or
+// exactly at it) make it fail, with `softening` > 0 the charges are softened
+// instead. Along the periodic axes the charges act through their nearest images.
+#[derive(Clone, Copy)]
+struct Query {
+    point: XY<f64>,
+    theta: f64,
+    min_distance: f64,
+    softening: f64,
+    periods: Periods,
+}
+
+// Quadtree of stationary charges, used to approximate the field of distant groups
+// of charges by their monopole and dipole moments. `theta` is the opening angle:
+// a node of size s at distance d is approximated if s / d < theta, so theta = 0
+// gives the exact direct sum.
+pub struct BarnesHut {
+    root: Option<Node>,
+    pub theta: f64,
+}
+
+impl BarnesHut {
+    pub fn new(stationary_charges: &[StationaryCharge], theta: f64) -> Self {
+        if stationary_charges.is_empty() {
+            return BarnesHut { root: None, theta };
+        }
+
+        let charges: Vec<(XY<f64>, f64)> = stationary_charges
+            .iter()
+            .map(|c| (XY { x: c.x, y: c.y }, c.q))
+            .collect();
+        let (mut min, mut max) = (charges[0].0, charges[0].0);
+        for (position, _) in &charges {
+            min.x = min.x.min(position.x);
+            min.y = min.y.min(position.y);
+            max.x = max.x.max(position.x);
+            max.y = max.y.max(position.y);
+        }
+        let center = (min + max) * 0.5;
+        // slightly larger than needed, so charges on the edge are inside
+        let half_size = ((max.x - min.x).max(max.y - min.y) / 2.).max(1.) * 1.001;
+
+        BarnesHut {
+            root: Some(Node::new(charges, center, half_size, 0)),
+            theta,
+        }
+    }
+
+    // Field intensity and potential at (x, y), or None if a charge is closer than
+    // `min_distance` (or exactly at the point), same as the direct sums (also
+    // with the nearest images along the periodic axes).
+    pub fn field_at(
+        &self,
+        x: f64,
+        y: f64,
+        min_distance: f64,
+        softening: f64,
+        periods: Periods,
+    ) -> Option<(XY<f64>, f64)> {
+        let mut intensity = XY::zero();
+        let mut potential = 0.;
+        if let Some(root) = &self.root {
+            let query = Query {
+                point: XY { x, y },
+                theta: self.theta,
+                min_distance,
+                softening,
+                periods,
+            };
+            if !root.accumulate(&query, &mut intensity, &mut potential) {
+                return None;
+            }
+        }
+        Some((intensity, potential))
+    }
+}
@@ -0,0 +1,79 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::lib::helpers::XY;
+
+// What happens to a movable charge crossing an edge of the grid. The edges
+// lie half a cell outside of the outermost cell centers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum Boundary {
+    // the charge keeps moving outside of the grid
+    #[value(name = "otwarty")]
+    #[serde(rename = "otwarty")]
+    Open,
+    // the charge is stopped and counted as escaped
+    #[value(name = "pochlaniajacy")]
+    #[serde(rename = "pochlaniajacy")]
+    Absorb,
+    // specular reflection, the normal component of the velocity is reversed
+    #[value(name = "odbijajacy")]
+    #[serde(rename = "odbijajacy")]
+    Reflect,
+    // the charge enters again at the opposite edge, the point charges then act
+    // through their nearest image (should be set on both opposite edges)
+    #[value(name = "okresowy")]
+    #[serde(rename = "okresowy")]
+    Periodic,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Boundaries {
+    #[serde(rename = "lewy")]
+    pub left: Boundary,
+    #[serde(rename = "prawy")]
+    pub right: Boundary,
+    #[serde(rename = "gorny")]
+    pub top: Boundary,
+    #[serde(rename = "dolny")]
+    pub bottom: Boundary,
+}
+
+impl Default for Boundaries {
+    fn default() -> Self {
+        Boundaries::all(Boundary::Open)
+    }
+}
+
+impl Boundaries {
+    pub fn all(boundary: Boundary) -> Self {
+        Boundaries {
+            left: boundary,
+            right: boundary,
+            top: boundary,
+            bottom: boundary,
+        }
+    }
+}
+
+// Periods of the grid along x and y [cells], None for the axes without
+// periodic edges. Used for the minimum image convention.
+#[derive(Clone, Copy, Default)]
+pub struct Periods {
+    pub x: Option<f64>,
+    pub y: Option<f64>,
+}
+
+impl Periods {
+    // the shortest of the periodic images of the displacement d (in cells)
+    pub fn wrap(&self, d: XY<f64>) -> XY<f64> {
+        let wrap = |d: f64, period: Option<f64>| match period {
+            Some(period) => d - period * (d / period).round(),
+            None => d,
+        };
+        XY {
+            x: wrap(d.x, self.x),
+            y: wrap(d.y, self.y),
+        }
+    }
+}
@@ -1,13 +1,36 @@
 use std::{
     cmp::min,
-    f64::INFINITY,
     fs,
     io::{BufWriter, Write},
 };
 
+use rayon::prelude::*;
+
 use crate::{
-    lib::helpers::{print_color, K, XY},
-    movable_charge::{field_intensity_movable, MovableCharge},
+    barnes_hut::BarnesHut,
+    boundary::{Boundaries, Boundary, Periods},
+    charge_distribution::ChargeDistribution,
+    collision::{CollisionModel, MAX_BOUNCES},
+    conductor::Conductor,
+    dielectric::Dielectric,
+    external_field::{external_intensity_potential, ExternalField},
+    integrator::{Forces, Integrator, IntegratorKind, State},
+    interpolation::{interpolate, FieldLookup},
+    lib::helpers::{print_color, K, SPEED_OF_LIGHT, XY},
+    magnetic_field::{magnetic_field_at, MagneticField},
+    metadata::{
+        header, RunInfo, GRID_COLUMNS, STATIONARY_COLUMNS, VTK_GRID_ARRAYS, VTK_MOVABLE_ARRAYS,
+        VTK_STATIONARY_ARRAYS,
+    },
+    movable_charge::{
+        self, field_intensity_movable, MovableCharge, COLLISION_DISTANCE, MAX_SPEED_FRACTION,
+    },
+    npy,
+    poisson::solve_poisson,
+    scenario::{read_text_file, text_records, Scenario, ScenarioError},
+    trajectory::{MovementEvent, MovementStep, TrajectoryWriter},
+    vtk::{self, DataArray},
+    waveform::Waveform,
 };
 
 #[derive(Clone)]
@@ -16,6 +39,13 @@ pub struct Cell {
     pub e: XY<f64>,
     // v = potencjał pola elektrycznego
     pub v: f64,
+    // the cell is a part of a conductor, its potential is fixed
+    pub conductor: bool,
+    // relative permittivity ε_r, 1 in vacuum
+    pub permittivity: f64,
+    // a stationary charge or distribution is close to the cell, so the charges
+    // moving near it have to be checked for collisions
+    pub near_source: bool,
 }
 
 struct CellData {
@@ -23,39 +53,151 @@ struct CellData {
     pub potential: f64,
 }
 
+// Stationary charges can be placed anywhere in the plane (in cells), also
+// between the cells and outside of the grid, they still contribute to the field
+// inside the grid.
 pub struct StationaryCharge {
-    pub x: usize,
-    pub y: usize,
+    pub x: f64,
+    pub y: f64,
+    // value of the charge at the current simulation time [C]
     pub q: f64,
+    // None for the charges that don't change in time
+    pub schedule: Option<ChargeSchedule>,
 }
 
-// MovementStep is used to track the movement of movable charges, so that they can be saved to a file (if user wants to)
-struct MovementStep {
-    pub x: f64,
-    pub y: f64,
-    pub v: XY<f64>,
-    pub a: XY<f64>,
+// q(t) = amplitude * waveform(t) + absorbed
+#[derive(Clone)]
+pub struct ChargeSchedule {
+    // the charge given in the scenario
+    pub amplitude: f64,
+    pub waveform: Waveform,
+    // charge of the movable charges absorbed so far, it doesn't follow the waveform
+    pub absorbed: f64,
+}
+
+impl StationaryCharge {
+    // value of the charge at time t [s]
+    fn value_at(&self, t: f64) -> f64 {
+        match &self.schedule {
+            Some(schedule) => schedule.amplitude * schedule.waveform.value(t) + schedule.absorbed,
+            None => self.q,
+        }
+    }
+}
+
+impl StationaryCharge {
+    // file format is
+    // <x> <y> <q>
+    pub fn vec_from_file(file: &str) -> Result<Vec<StationaryCharge>, Vec<ScenarioError>> {
+        let contents = read_text_file(file).map_err(|e| vec![e])?;
+        let mut errors = Vec::new();
+        let mut stationary_charges = Vec::new();
+
+        for record in text_records(file, &contents, 3, &mut errors) {
+            // all values are parsed, so every wrong value in the line is reported
+            let x = record.parse(0, "x", &mut errors);
+            let y = record.parse(1, "y", &mut errors);
+            let q = record.parse(2, "q", &mut errors);
+            if let (Some(x), Some(y), Some(q)) = (x, y, q) {
+                stationary_charges.push(StationaryCharge {
+                    x,
+                    y,
+                    q,
+                    schedule: None,
+                });
+            }
+        }
+
+        // lines with a wrong number of values are reported before the values
+        errors.sort_by_key(ScenarioError::line);
+        if errors.is_empty() {
+            Ok(stationary_charges)
+        } else {
+            Err(errors)
+        }
+    }
 }
 
+// final state, intermediate states with their step lengths and the suggested
+// adaptive step, or the indices of the collided charges
+type GroupResult = Result<(State, Vec<(State, f64)>, f64), Vec<usize>>;
+
 pub struct CellGrid {
     w: usize,
     h: usize,
+    // physical size of a single cell [m], positions are stored in cells
+    // while velocities, accelerations and fields are in SI units
+    pub cell_size: f64,
     pub cells: Vec<Vec<Cell>>,
     pub stationary_charges: Vec<StationaryCharge>,
     pub movable_charges: Vec<MovableCharge>,
+    // if set, the field is approximated using the tree instead of direct sums
+    barnes_hut: Option<BarnesHut>,
+    pub integrator: IntegratorKind,
+    // relative error tolerance for the adaptive integrator
+    pub tolerance: f64,
+    // whether movable charges act on each other (N-body mode)
+    pub interactions: bool,
+    // uniform / analytic fields added to the field of the stationary charges
+    pub external_fields: Vec<ExternalField>,
+    // lines, arcs and rectangles of charge, acting together with the stationary charges
+    pub charge_distributions: Vec<ChargeDistribution>,
+    // static Bz field, acts on the moving charges with the force q v × B
+    pub magnetic_fields: Vec<MagneticField>,
+    // evolve the momentum with the Lorentz factor instead of the classical velocity
+    pub relativistic: bool,
+    // electrodes at fixed potentials, solved for on the grid
+    pub conductors: Vec<Conductor>,
+    // regions of linear dielectric, also solved for on the grid
+    pub dielectrics: Vec<Dielectric>,
+    // what happens to the movable charges at the edges of the grid
+    pub boundaries: Boundaries,
+    // if set, the field in the cells (filled by populate_field) includes the
+    // conductors and dielectrics, so it can't be replaced by the direct sums
+    pub grid_field: bool,
+    // whether the charges are moved by the direct sums or by the field
+    // interpolated from the cells
+    pub field_lookup: FieldLookup,
+    last_field_lookup: FieldLookup,
+    // relative tolerance of the SOR iterations
+    pub poisson_tolerance: f64,
+    // what happens when a movable charge comes too close to a point charge
+    pub collision_model: CollisionModel,
+    // collision radius, or the softening length of the Plummer model [cells]
+    pub collision_radius: f64,
+    // set once the cells hold the field, so that it's recomputed when the
+    // sources change during the simulation
+    cells_populated: bool,
+    // bumped every time the field in the cells is recomputed
+    pub field_version: usize,
+    // set when the time-varying charges changed, but the cells weren't
+    // recomputed yet (see refresh_outdated_field)
+    field_outdated: bool,
+    // simulation time [s], the time-varying charges are evaluated at it
+    pub time_elapsed: f64,
+    // set when the cached accelerations of the movable charges may not match
+    // their positions anymore (new charges, toggled interactions, collisions)
+    accelerations_outdated: bool,
+    last_interactions: bool,
     // movement stuff
-    pub track_movement: bool,
-    movement_history: Vec<Vec<MovementStep>>,
+    // writes the states of the movable charges while they move, if set
+    trajectory: Option<TrajectoryWriter>,
+    // settings of the run described in the metadata of the output files
+    run_info: RunInfo,
+    next_charge_id: usize,
 }
 
 impl CellGrid {
-    fn new(x: usize, y: usize, save_movement: bool) -> Self {
+    fn new(x: usize, y: usize, cell_size: f64) -> Self {
         let cells = vec![
             vec![
                 Cell {
                     q: 0.0,
                     e: XY { x: 0.0, y: 0.0 },
-                    v: 0.0
+                    v: 0.0,
+                    conductor: false,
+                    permittivity: 1.0,
+                    near_source: false,
                 };
                 x
             ];
@@ -64,11 +206,43 @@ impl CellGrid {
         CellGrid {
             w: x,
             h: y,
+            cell_size,
             cells,
             stationary_charges: Vec::new(),
             movable_charges: Vec::new(),
-            track_movement: save_movement,
-            movement_history: Vec::new(),
+            barnes_hut: None,
+            integrator: IntegratorKind::Verlet,
+            tolerance: 1e-6,
+            interactions: false,
+            external_fields: Vec::new(),
+            charge_distributions: Vec::new(),
+            magnetic_fields: Vec::new(),
+            relativistic: false,
+            conductors: Vec::new(),
+            dielectrics: Vec::new(),
+            boundaries: Boundaries::default(),
+            grid_field: false,
+            field_lookup: FieldLookup::Direct,
+            last_field_lookup: FieldLookup::Direct,
+            poisson_tolerance: 1e-8,
+            collision_model: CollisionModel::Stop,
+            collision_radius: COLLISION_DISTANCE,
+            cells_populated: false,
+            field_version: 0,
+            field_outdated: false,
+            time_elapsed: 0.,
+            accelerations_outdated: false,
+            last_interactions: false,
+            trajectory: None,
+            run_info: RunInfo {
+                scenario_hash: String::new(),
+                delta_t: 0.,
+                integrator: IntegratorKind::Verlet,
+                width: x,
+                height: y,
+                cell_size,
+            },
+            next_charge_id: 0,
         }
     }
 
@@ -76,45 +250,680 @@ impl CellGrid {
         (self.w, self.h)
     }
 
-    pub fn new_from_file(file: &str, save_movement: bool) -> Self {
-        let mut grid = CellGrid::new(256, 256, save_movement);
+    pub fn from_scenario(scenario: &Scenario, save_movement: bool) -> Self {
+        let mut grid = CellGrid::new(
+            scenario.grid.width,
+            scenario.grid.height,
+            scenario.grid.cell_size,
+        );
+        grid.run_info = RunInfo::from_scenario(scenario);
+        if save_movement {
+            grid.trajectory = Some(TrajectoryWriter::new(
+                "output",
+                scenario.output.every_n_steps,
+                scenario.output.format,
+                grid.run_info.clone(),
+            ));
+        }
+        grid.integrator = scenario.integrator();
+        grid.tolerance = scenario.simulation.tolerance;
+        grid.interactions = scenario.simulation.interactions;
+        grid.external_fields = scenario.external_fields.clone();
+        grid.charge_distributions = scenario.charge_distributions.clone();
+        grid.magnetic_fields = scenario.magnetic_fields.clone();
+        grid.relativistic = scenario.simulation.relativistic;
+        grid.poisson_tolerance = scenario.simulation.poisson_tolerance;
+        grid.boundaries = scenario.boundaries;
+        grid.collision_model = scenario.simulation.collision_model;
+        grid.collision_radius = scenario.simulation.collision_radius;
+        for conductor in &scenario.conductors {
+            grid.add_conductor(conductor.clone());
+        }
+        for dielectric in &scenario.dielectrics {
+            grid.add_dielectric(dielectric.clone());
+        }
+        // conductors and dielectrics have no effect on the direct sums
+        grid.grid_field = scenario.simulation.poisson_solver
+            || !grid.conductors.is_empty()
+            || !grid.dielectrics.is_empty();
+        grid.field_lookup = match scenario.simulation.field_lookup {
+            FieldLookup::Direct if grid.grid_field => FieldLookup::Bilinear,
+            lookup => lookup,
+        };
+        grid.last_field_lookup = grid.field_lookup;
 
-        let contents = fs::read_to_string(file).expect("Nie można odczytać pliku");
-        let lines = contents.lines();
-        // let linecount: usize = lines.next().expect("Nie można odczytać liczby ładunków").parse().expect("Nie można przekonwertować liczby ładunków");
-        for (i, line) in lines.enumerate().filter(|(_, line)| !line.starts_with('#')) {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() != 3 {
-                panic!("Nieprawidłowa ilość wartości w linijce {}", i + 2);
-            }
-            // read the values, and in case of error, print the line number
-            let x: usize = parts[0].parse().unwrap_or_else(|_| {
-                panic!("Wystąpił problem przy odczytywaniu X w linii {}", i + 2)
-            });
-            let y: usize = parts[1].parse().unwrap_or_else(|_| {
-                panic!("Wystąpił problem przy odczytywaniu Y w linii {}", i + 2)
-            });
-            let q = parts[2].parse().unwrap_or_else(|_| {
-                panic!("Wystąpił problem przy odczytywaniu Q w linii {}", i + 2)
-            });
-            grid.cells[y][x].q = q;
-            grid.stationary_charges.push(StationaryCharge { x, y, q });
+        for charge in &scenario.stationary_charges {
+            grid.add_stationary_charge(charge.to_charge());
+        }
+        for charge in &scenario.movable_charges {
+            grid.add_movable_charge(charge.to_charge());
         }
-        // if grid.stationary_charges.len() != linecount {
-        //     panic!("Liczba ładunków nie zgadza się z liczbą w pierwszej linii!");
-        // }
         grid
     }
 
-    pub fn populate_field(&mut self) {
+    // the cell containing the point (x, y) (in cells), None if outside of the grid
+    pub fn cell_index(&self, x: f64, y: f64) -> Option<(usize, usize)> {
+        let (x, y) = (x.round(), y.round());
+        if x < 0. || y < 0. || x >= self.w as f64 || y >= self.h as f64 {
+            return None;
+        }
+        Some((x as usize, y as usize))
+    }
+
+    pub fn add_stationary_charge(&mut self, charge: StationaryCharge) {
+        // the charge is added to the nearest cell, charges outside of the grid
+        // only contribute to the field
+        if let Some((x, y)) = self.cell_index(charge.x, charge.y) {
+            self.cells[y][x].q += charge.q;
+        }
+        self.stationary_charges.push(charge);
+    }
+
+    // marks the cells with their centers inside the conductor, the potential of
+    // a cell covered by a few conductors is taken from the last one
+    pub fn add_conductor(&mut self, conductor: Conductor) {
+        for (y, row) in self.cells.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                if conductor.shape.contains(x as f64, y as f64) {
+                    cell.conductor = true;
+                }
+            }
+        }
+        self.conductors.push(conductor);
+    }
+
+    // sets the permittivity of the cells with their centers inside the region,
+    // overlapping regions are overwritten by the later ones
+    pub fn add_dielectric(&mut self, dielectric: Dielectric) {
+        for (y, row) in self.cells.iter_mut().enumerate() {
+            for (x, cell) in row.iter_mut().enumerate() {
+                if dielectric.shape.contains(x as f64, y as f64) {
+                    cell.permittivity = dielectric.permittivity;
+                }
+            }
+        }
+        self.dielectrics.push(dielectric);
+    }
+
+    // ε_r of the cell nearest to (x, y) (in cells), vacuum outside of the grid
+    pub fn permittivity_at(&self, x: f64, y: f64) -> f64 {
+        self.cell_index(x, y)
+            .map_or(1., |(x, y)| self.cells[y][x].permittivity)
+    }
+
+    // largest ε_r in the grid, used to scale the GUI background
+    pub fn permittivity_max(&self) -> f64 {
+        self.cells
+            .iter()
+            .flatten()
+            .map(|cell| cell.permittivity)
+            .fold(1., f64::max)
+    }
+
+    // potential of the conductor covering the cell (x, y)
+    fn conductor_potential(&self, x: usize, y: usize) -> Option<f64> {
+        self.conductors
+            .iter()
+            .rev()
+            .find(|conductor| conductor.shape.contains(x as f64, y as f64))
+            .map(|conductor| conductor.potential)
+    }
+
+    // The point charges act through their nearest image along the axes with a
+    // periodic edge (also in the Barnes-Hut tree). The distributions, external
+    // fields and the field solved on the grid don't repeat, see
+    // non_periodic_sources.
+    fn periods(&self) -> Periods {
+        let periodic =
+            |a: Boundary, b: Boundary| a == Boundary::Periodic || b == Boundary::Periodic;
+        Periods {
+            x: periodic(self.boundaries.left, self.boundaries.right).then_some(self.w as f64),
+            y: periodic(self.boundaries.top, self.boundaries.bottom).then_some(self.h as f64),
+        }
+    }
+
+    // Sources that ignore the periodic edges, so the user can be warned that
+    // the field isn't periodic
+    pub fn non_periodic_sources(&self) -> Vec<&'static str> {
+        let periods = self.periods();
+        if periods.x.is_none() && periods.y.is_none() {
+            return Vec::new();
+        }
+        let mut sources = Vec::new();
+        if !self.charge_distributions.is_empty() {
+            sources.push("ładunki rozciągłe");
+        }
+        if self.grid_field {
+            sources.push("pole liczone na siatce (przewodniki, dielektryki, solwer Poissona)");
+        }
+        sources
+    }
+
+    // softening length of the point charges [cells], zero unless the Plummer
+    // model is selected
+    fn softening(&self) -> f64 {
+        match self.collision_model {
+            CollisionModel::Soften => self.collision_radius,
+            _ => 0.,
+        }
+    }
+
+    // Applies the edges of the grid to a charge after a step. The edges lie
+    // half a cell outside of the outermost cells, the top edge is at y = -0.5.
+    // Returns true if the charge was reflected or moved to the opposite edge.
+    fn apply_boundaries(&mut self, i: usize) -> bool {
+        let (w, h) = (self.w as f64, self.h as f64);
+        let boundaries = self.boundaries;
+        let charge = &mut self.movable_charges[i];
+        let mut moved = false;
+        for (position, velocity, size, low, high) in [
+            (
+                &mut charge.x,
+                &mut charge.v.x,
+                w,
+                boundaries.left,
+                boundaries.right,
+            ),
+            (
+                &mut charge.y,
+                &mut charge.v.y,
+                h,
+                boundaries.top,
+                boundaries.bottom,
+            ),
+        ] {
+            let (min, max) = (-0.5, size - 0.5);
+            let boundary = if *position < min {
+                low
+            } else if *position > max {
+                high
+            } else {
+                continue;
+            };
+            match boundary {
+                Boundary::Open => {}
+                Boundary::Absorb => {
+                    charge.escaped = true;
+                    charge.should_move = false;
+                }
+                // a charge that can't be brought back to the grid (infinite
+                // position after a diverging step) is stopped as well
+                Boundary::Reflect | Boundary::Periodic if !position.is_finite() => {
+                    charge.escaped = true;
+                    charge.should_move = false;
+                }
+                Boundary::Reflect => {
+                    // mirrored at the edges, a charge faster than a cell per step
+                    // may cross them more than once: the position is folded into
+                    // a period of two widths, with the velocity reversed for
+                    // every crossing
+                    let offset = *position - min;
+                    let folded = offset.rem_euclid(2. * size);
+                    *position = if folded < size {
+                        min + folded
+                    } else {
+                        min + 2. * size - folded
+                    };
+                    if (offset / size).floor().rem_euclid(2.) == 1. {
+                        *velocity = -*velocity;
+                    }
+                    moved = true;
+                }
+                Boundary::Periodic => {
+                    *position = (*position - min).rem_euclid(size) + min;
+                    moved = true;
+                }
+            }
+        }
+        moved
+    }
+
+    // builds the Barnes-Hut tree of the stationary charges, which is then used
+    // by all field evaluations instead of the direct sums
+    pub fn use_barnes_hut(&mut self, theta: f64) {
+        self.barnes_hut = Some(BarnesHut::new(&self.stationary_charges, theta));
+    }
+
+    // Recomputes the field in the cells (if they were filled before) after a
+    // change of the sources or of the collision model, the percentiles and the
+    // background should then be refreshed as well (see field_version).
+    pub fn refresh_field(&mut self) {
+        if self.cells_populated {
+            self.populate_field();
+        }
+        self.field_version += 1;
+        self.field_outdated = false;
+        self.accelerations_outdated = true;
+    }
+
+    // Recomputes the cells if the time-varying charges changed since they were
+    // filled. The charges are moved by the direct sums then, so it's enough to
+    // do it once before drawing.
+    pub fn refresh_outdated_field(&mut self) {
+        if self.field_outdated {
+            self.refresh_field();
+        }
+    }
+
+    // Sets the time-varying stationary charges (and their cells) to their
+    // values at time t [s], returns false if there are none.
+    fn update_stationary_charges(&mut self, t: f64) -> bool {
+        let mut changed = false;
+        for i in 0..self.stationary_charges.len() {
+            let charge = &self.stationary_charges[i];
+            if charge.schedule.is_none() {
+                continue;
+            }
+            let (x, y, q) = (charge.x, charge.y, charge.value_at(t));
+            let difference = q - charge.q;
+            self.stationary_charges[i].q = q;
+            if let Some((x, y)) = self.cell_index(x, y) {
+                self.cells[y][x].q += difference;
+            }
+            changed = true;
+        }
+        if changed {
+            if let Some(tree) = &self.barnes_hut {
+                self.barnes_hut = Some(BarnesHut::new(&self.stationary_charges, tree.theta));
+            }
+        }
+        changed
+    }
+
+    // Adds the charge of an absorbed movable charge to the stationary charge
+    // `target` and to its cell.
+    fn absorb_charge(&mut self, target: usize, q: f64) {
+        let (x, y) = (
+            self.stationary_charges[target].x,
+            self.stationary_charges[target].y,
+        );
+        self.stationary_charges[target].q += q;
+        if let Some(schedule) = &mut self.stationary_charges[target].schedule {
+            schedule.absorbed += q;
+        }
+        if let Some((x, y)) = self.cell_index(x, y) {
+            self.cells[y][x].q += q;
+        }
+        if let Some(tree) = &self.barnes_hut {
+            self.barnes_hut = Some(BarnesHut::new(&self.stationary_charges, tree.theta));
+        }
+        self.refresh_field();
+    }
+
+    // The stationary point charge the movable charge `i` has collided with
+    // during a step of delta_t: the nearest one within the collision radius
+    // plus the distance the charge could have covered. None if the collision
+    // was with a distribution, a conductor or another movable charge.
+    fn collision_target(&self, i: usize, delta_t: f64) -> Option<usize> {
+        let charge = &self.movable_charges[i];
+        let reach = self.collision_radius
+            + (charge.v.length() * delta_t + 0.5 * charge.a.length() * delta_t.powi(2))
+                / self.cell_size;
+        self.stationary_charges
+            .iter()
+            .enumerate()
+            .map(|(j, c)| {
+                let d = self.periods().wrap(XY {
+                    x: charge.x - c.x,
+                    y: charge.y - c.y,
+                });
+                (j, d.length())
+            })
+            .filter(|&(_, distance)| distance < reach)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(j, _)| j)
+    }
+
+    // Elastic bounce of the movable charge `i` after a collision during a step
+    // of delta_t: the velocity is mirrored about the line joining the centers
+    // of the nearest stationary charge it is approaching (within the reach of
+    // collision_target), so the charge leaves the sphere with the same speed.
+    // Returns false if it isn't approaching any of them (e.g. it is already
+    // leaving, but the field pulls it back), then the velocity is unchanged.
+    fn reflect_velocity(&mut self, i: usize, delta_t: f64) -> bool {
+        let charge = &self.movable_charges[i];
+        let reach = self.collision_radius
+            + (charge.v.length() * delta_t + 0.5 * charge.a.length() * delta_t.powi(2))
+                / self.cell_size;
+        let approached = self
+            .stationary_charges
+            .iter()
+            .filter_map(|c| {
+                let n = self.periods().wrap(XY {
+                    x: charge.x - c.x,
+                    y: charge.y - c.y,
+                });
+                let distance = n.length();
+                let n = n * (1. / distance);
+                let along = charge.v.x * n.x + charge.v.y * n.y;
+                (distance < reach && along < 0.).then_some((n, along, distance))
+            })
+            .min_by(|a, b| a.2.total_cmp(&b.2));
+        match approached {
+            Some((n, along, _)) => {
+                self.movable_charges[i].v = charge.v - n * (2. * along);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // field intensity [V/m] at (x, y) (in cells) used to move the charges,
+    // None if the point is too close to a stationary charge or distribution
+    pub fn intensity_at(&self, x: f64, y: f64) -> Option<XY<f64>> {
+        if self.field_lookup != FieldLookup::Direct {
+            if let Some((intensity, _)) = self.interpolated_field(x, y) {
+                if self.near_source(x, y) {
+                    return None;
+                }
+                // With a radius below √2 cells the stencil may still include
+                // the cell of a point charge (infinite field), the direct sums
+                // are used there instead
+                if intensity.x.is_finite() && intensity.y.is_finite() {
+                    return Some(intensity);
+                }
+            }
+        }
+        // the field functions measure distances in cells, E ~ 1 / r^2
+        let intensity = match &self.barnes_hut {
+            Some(tree) => tree
+                .field_at(
+                    x,
+                    y,
+                    self.collision_radius,
+                    self.softening(),
+                    self.periods(),
+                )
+                .map(|(intensity, _)| intensity),
+            None => field_intensity_movable(
+                x,
+                y,
+                &self.stationary_charges,
+                self.collision_radius,
+                self.softening(),
+                self.periods(),
+            ),
+        }?;
+        let (extra_intensity, _) = extra_field(
+            &self.external_fields,
+            &self.charge_distributions,
+            x,
+            y,
+            self.cell_size,
+            self.collision_radius,
+        )?;
+        Some(intensity * (1. / self.cell_size.powi(2)) + extra_intensity)
+    }
+
+    // field intensity [V/m] and potential [V] at (x, y) (in cells),
+    // None only if the point is exactly at a stationary charge or distribution
+    pub fn intensity_potential_at(&self, x: f64, y: f64) -> Option<(XY<f64>, f64)> {
+        if self.field_lookup != FieldLookup::Direct {
+            if let Some((intensity, potential)) = self.interpolated_field(x, y) {
+                let finite = intensity.x.is_finite() && intensity.y.is_finite();
+                return (finite && potential.is_finite()).then_some((intensity, potential));
+            }
+        }
+        let (intensity, potential) = match &self.barnes_hut {
+            Some(tree) => tree.field_at(x, y, 0., self.softening(), self.periods()),
+            None => movable_charge::field_intensity_potential(
+                x,
+                y,
+                &self.stationary_charges,
+                0.,
+                self.softening(),
+                self.periods(),
+            ),
+        }?;
+        let (extra_intensity, extra_potential) = extra_field(
+            &self.external_fields,
+            &self.charge_distributions,
+            x,
+            y,
+            self.cell_size,
+            0.,
+        )?;
+        Some((
+            intensity * (1. / self.cell_size.powi(2)) + extra_intensity,
+            potential / self.cell_size + extra_potential,
+        ))
+    }
+
+    // Field intensity [V/m] and potential [V] at (x, y) (in cells), interpolated
+    // from the cells around it. None outside of the grid, where the direct sums
+    // are used instead. The bicubic stencil may reach a cell with a charge in
+    // it while the point itself is still outside of the collision distance,
+    // then the bilinear interpolation is used.
+    fn interpolated_field(&self, x: f64, y: f64) -> Option<(XY<f64>, f64)> {
+        let sample = |x: usize, y: usize| (self.cells[y][x].e, self.cells[y][x].v);
+        let (intensity, potential) = interpolate(self.field_lookup, self.w, self.h, x, y, sample)?;
+        if self.field_lookup == FieldLookup::Bicubic
+            && !(intensity.x.is_finite() && intensity.y.is_finite() && potential.is_finite())
+        {
+            return interpolate(FieldLookup::Bilinear, self.w, self.h, x, y, sample);
+        }
+        Some((intensity, potential))
+    }
+
+    // whether a charge at (x, y) (in cells) hits a conductor, or is closer than
+    // the collision distance to a stationary charge or distribution, used
+    // instead of the checks in the direct sums. The sources are only checked
+    // near the cells flagged by populate_field.
+    fn near_source(&self, x: f64, y: f64) -> bool {
+        if let Some((cx, cy)) = self.cell_index(x, y) {
+            let cell = &self.cells[cy][cx];
+            if cell.conductor {
+                return true;
+            }
+            if !cell.near_source {
+                return false;
+            }
+        }
+        // the softened point charges never collide
+        let stationary_charges: &[StationaryCharge] = if self.softening() > 0. {
+            &[]
+        } else {
+            &self.stationary_charges
+        };
+        near_source(
+            stationary_charges,
+            &self.charge_distributions,
+            x,
+            y,
+            self.collision_radius,
+            self.periods(),
+        )
+    }
+
+    // Bz [T] at (x, y) (in cells)
+    pub fn magnetic_field_at(&self, x: f64, y: f64) -> f64 {
+        magnetic_field_at(&self.magnetic_fields, x, y, self.cell_size)
+    }
+
+    // largest |Bz| in the cells of the grid, used to scale the GUI background
+    pub fn magnetic_field_max(&self) -> f64 {
+        (0..self.h)
+            .flat_map(|y| (0..self.w).map(move |x| (x as f64, y as f64)))
+            .map(|(x, y)| self.magnetic_field_at(x, y).abs())
+            .fold(0., f64::max)
+    }
+
+    // Compares the Barnes-Hut approximation with the direct sums in every cell of
+    // the grid, returns the maximal relative error of the intensity and potential
+    pub fn barnes_hut_error(&self) -> Option<(f64, f64)> {
+        let tree = self.barnes_hut.as_ref()?;
+        let errors = (0..self.h)
+            .into_par_iter()
+            .map(|y| {
+                let mut max_intensity_error: f64 = 0.;
+                let mut max_potential_error: f64 = 0.;
+                for x in 0..self.w {
+                    let (x, y) = (x as f64, y as f64);
+                    let direct = movable_charge::field_intensity_potential(
+                        x,
+                        y,
+                        &self.stationary_charges,
+                        0.,
+                        0.,
+                        Periods::default(),
+                    );
+                    let (Some((intensity, potential)), Some((approx_intensity, approx_potential))) =
+                        (direct, tree.field_at(x, y, 0., 0., Periods::default()))
+                    else {
+                        continue;
+                    };
+                    if intensity.length() > 0. {
+                        let error = (approx_intensity - intensity).length() / intensity.length();
+                        max_intensity_error = max_intensity_error.max(error);
+                    }
+                    if potential != 0. {
+                        let error = ((approx_potential - potential) / potential).abs();
+                        max_potential_error = max_potential_error.max(error);
+                    }
+                }
+                (max_intensity_error, max_potential_error)
+            })
+            .reduce(|| (0., 0.), |a, b| (a.0.max(b.0), a.1.max(b.1)));
+        Some(errors)
+    }
+
+    // Fills the cells with the field of all sources. With the grid solver the
+    // conductors and dielectrics are included as well, then the number of SOR iterations and
+    // whether they converged is returned.
+    pub fn populate_field(&mut self) -> Option<(usize, bool)> {
+        let barnes_hut = &self.barnes_hut;
+        let stationary_charges = &self.stationary_charges;
+        let external_fields = &self.external_fields;
+        let charge_distributions = &self.charge_distributions;
+        let cell_size = self.cell_size;
+        let periods = self.periods();
+        let softening = self.softening();
+        let collision_radius = self.collision_radius;
+        // the softened point charges never collide
+        let colliding_charges: &[StationaryCharge] = if softening > 0. {
+            &[]
+        } else {
+            stationary_charges
+        };
+        // every cell is independent, so the rows are computed in parallel
+        self.cells.par_iter_mut().enumerate().for_each(|(y, row)| {
+            for (x, cell) in row.iter_mut().enumerate() {
+                let cell_data = match barnes_hut {
+                    Some(tree) => match tree.field_at(x as f64, y as f64, 0., softening, periods) {
+                        Some((intensity, potential)) => CellData {
+                            intensity,
+                            potential,
+                        },
+                        None => CellData {
+                            intensity: XY {
+                                x: f64::INFINITY,
+                                y: f64::INFINITY,
+                            },
+                            potential: f64::INFINITY,
+                        },
+                    },
+                    None => field_intensity_potential(stationary_charges, x, y, softening, periods),
+                };
+                let (extra_intensity, extra_potential) = extra_field(
+                    external_fields,
+                    charge_distributions,
+                    x as f64,
+                    y as f64,
+                    cell_size,
+                    0.,
+                )
+                .unwrap_or((
+                    XY {
+                        x: f64::INFINITY,
+                        y: f64::INFINITY,
+                    },
+                    f64::INFINITY,
+                ));
+                // convert from "per cell" to SI units
+                cell.e.x = cell_data.intensity.x / cell_size.powi(2) + extra_intensity.x;
+                cell.e.y = cell_data.intensity.y / cell_size.powi(2) + extra_intensity.y;
+                cell.v = cell_data.potential / cell_size + extra_potential;
+                // a point nearest to the cell is at most sqrt(2) / 2 away from it
+                cell.near_source = near_source(
+                    colliding_charges,
+                    charge_distributions,
+                    x as f64,
+                    y as f64,
+                    collision_radius + 1.,
+                    periods,
+                );
+            }
+        });
+        self.cells_populated = true;
+        if self.grid_field {
+            Some(self.solve_grid_field())
+        } else {
+            None
+        }
+    }
+
+    // The potential of the free charges in vacuum is already in the cells, the
+    // charges induced on the conductors and the polarization charges of the
+    // dielectrics add a correction φ. It is harmonic in vacuum (the sources are
+    // already accounted for), equal to V_conductor - V_free on the conductors
+    // and zero just outside of the grid (the influence vanishes far away).
+    // E = -∇φ is then added to the intensity using central differences. Only
+    // the stationary sources induce charges, the movable charges don't change
+    // the solution.
+    fn solve_grid_field(&mut self) -> (usize, bool) {
+        let (w, h) = (self.w, self.h);
+        let fixed: Vec<Option<f64>> = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let cell = &self.cells[y][x];
+                if !cell.conductor {
+                    return None;
+                }
+                let potential = self.conductor_potential(x, y)?;
+                // a charge inside the conductor, nothing sensible to correct
+                Some(if cell.v.is_finite() {
+                    potential - cell.v
+                } else {
+                    0.
+                })
+            })
+            .collect();
+        let cells = self.cells.iter().flatten();
+        let permittivity: Vec<f64> = cells.clone().map(|cell| cell.permittivity).collect();
+        let potential: Vec<f64> = cells.map(|cell| cell.v).collect();
+        let solution = solve_poisson(
+            w,
+            h,
+            &permittivity,
+            &potential,
+            &fixed,
+            self.poisson_tolerance,
+        );
+        let phi = |x: isize, y: isize| {
+            if x < 0 || y < 0 || x >= w as isize || y >= h as isize {
+                0.
+            } else {
+                solution.phi[y as usize * w + x as usize]
+            }
+        };
+        let cell_size = self.cell_size;
         for (y, row) in self.cells.iter_mut().enumerate() {
             for (x, cell) in row.iter_mut().enumerate() {
-                let cell_data = field_intensity_potential(&self.stationary_charges, x, y);
-                cell.e.x = cell_data.intensity.x;
-                cell.e.y = cell_data.intensity.y;
-                cell.v = cell_data.potential;
+                let (x, y) = (x as isize, y as isize);
+                if cell.conductor {
+                    // no field inside of a conductor
+                    cell.v += phi(x, y);
+                    cell.e = XY::zero();
+                    continue;
+                }
+                cell.v += phi(x, y);
+                cell.e.x -= (phi(x + 1, y) - phi(x - 1, y)) / (2. * cell_size);
+                cell.e.y -= (phi(x, y + 1) - phi(x, y - 1)) / (2. * cell_size);
             }
         }
+        // the cached accelerations were computed without the correction
+        self.accelerations_outdated = true;
+        (solution.iterations, solution.converged)
     }
 
     pub fn field_percentiles(&self, percentile: f64) -> (f64, f64) {
@@ -151,25 +960,176 @@ impl CellGrid {
     pub fn save_grid_to_file(&self, file: &str) {
         let output_file_raw = fs::File::create(file).expect("Nie można utworzyć pliku");
         let mut output_file = BufWriter::new(output_file_raw);
+        writeln!(output_file, "{}", header(&GRID_COLUMNS)).expect("Nie można zapisać do pliku");
+        self.run_info
+            .write_sidecar(file, &GRID_COLUMNS, Some(self.time_elapsed));
         for (y, row) in self.cells.iter().enumerate() {
             for (x, cell) in row.iter().enumerate() {
-                // format: x, y, charge, Ex, Ey, E, V
+                // format: x [m], y [m], charge, Ex, Ey, E, V, ε_r
                 writeln!(
                     output_file,
-                    "{}, {}, {}, {}, {}, {}, {}",
-                    x,
-                    y,
+                    "{}, {}, {}, {}, {}, {}, {}, {}",
+                    x as f64 * self.cell_size,
+                    y as f64 * self.cell_size,
                     cell.q,
                     cell.e.x,
                     cell.e.y,
                     cell.e.length(),
-                    cell.v
+                    cell.v,
+                    cell.permittivity
                 )
                 .expect("Nie można zapisać do pliku");
             }
         }
     }
 
+    // The grid file only has the charge summed in each cell, so the exact
+    // positions of the stationary charges are saved separately
+    pub fn save_stationary_charges_to_file(&self, file: &str) {
+        let output_file_raw = fs::File::create(file).expect("Nie można utworzyć pliku");
+        let mut output_file = BufWriter::new(output_file_raw);
+        writeln!(output_file, "{}", header(&STATIONARY_COLUMNS))
+            .expect("Nie można zapisać do pliku");
+        self.run_info
+            .write_sidecar(file, &STATIONARY_COLUMNS, Some(self.time_elapsed));
+        for charge in &self.stationary_charges {
+            // format: x [m], y [m], charge
+            writeln!(
+                output_file,
+                "{}, {}, {}",
+                charge.x * self.cell_size,
+                charge.y * self.cell_size,
+                charge.q
+            )
+            .expect("Nie można zapisać do pliku");
+        }
+    }
+
+    // Saves q, Ex, Ey, |E|, V and ε_r of the cells as separate .npy arrays
+    // ([directory]/q.npy, ...) of shape (height, width), element [y][x] is the
+    // cell at (x, y)
+    pub fn save_grid_to_npy(&self, directory: &str) {
+        let values: [fn(&Cell) -> f64; 6] = [
+            |cell| cell.q,
+            |cell| cell.e.x,
+            |cell| cell.e.y,
+            |cell| cell.e.length(),
+            |cell| cell.v,
+            |cell| cell.permittivity,
+        ];
+        // the columns of the grid file after x and y
+        for (value, column) in values.iter().zip(&GRID_COLUMNS[2..]) {
+            let path = format!("{}/{}.npy", directory, column.0);
+            let array: Vec<f64> = self.cells.iter().flatten().map(value).collect();
+            npy::save_array(&path, &[self.h, self.w], &array);
+            self.run_info
+                .write_sidecar(&path, &[*column], Some(self.time_elapsed));
+        }
+    }
+
+    // the stationary charges as a .npy array of shape (n, 3): x [m], y [m], q
+    pub fn save_stationary_charges_to_npy(&self, file: &str) {
+        let array: Vec<f64> = self
+            .stationary_charges
+            .iter()
+            .flat_map(|charge| {
+                [
+                    charge.x * self.cell_size,
+                    charge.y * self.cell_size,
+                    charge.q,
+                ]
+            })
+            .collect();
+        npy::save_array(file, &[self.stationary_charges.len(), 3], &array);
+        self.run_info
+            .write_sidecar(file, &STATIONARY_COLUMNS, Some(self.time_elapsed));
+    }
+
+    // q, V, ε_r and the vector E of the cells, row by row
+    fn vtk_grid_arrays(&self) -> Vec<DataArray> {
+        let cells = || self.cells.iter().flatten();
+        vec![
+            DataArray::scalars(VTK_GRID_ARRAYS[0].0, cells().map(|cell| cell.q)),
+            DataArray::scalars(VTK_GRID_ARRAYS[1].0, cells().map(|cell| cell.v)),
+            DataArray::scalars(VTK_GRID_ARRAYS[2].0, cells().map(|cell| cell.permittivity)),
+            DataArray::vectors(VTK_GRID_ARRAYS[3].0, cells().map(|cell| cell.e)),
+        ]
+    }
+
+    // Saves the cells as a legacy VTK file (structured points with the spacing
+    // of the cell size), for ParaView
+    pub fn save_grid_to_vtk(&self, file: &str) {
+        let title = format!("Pole na siatce, t = {:e} s", self.time_elapsed);
+        vtk::save(file, |writer| {
+            vtk::write_structured_points(
+                writer,
+                &title,
+                (self.w, self.h),
+                self.cell_size,
+                &self.vtk_grid_arrays(),
+            )
+        });
+        self.run_info
+            .write_sidecar(file, &VTK_GRID_ARRAYS, Some(self.time_elapsed));
+    }
+
+    // the stationary charges as separate points of a legacy VTK file
+    pub fn save_stationary_charges_to_vtk(&self, file: &str) {
+        let points: Vec<XY<f64>> = self
+            .stationary_charges
+            .iter()
+            .map(|charge| XY {
+                x: charge.x * self.cell_size,
+                y: charge.y * self.cell_size,
+            })
+            .collect();
+        let charges = self.stationary_charges.iter().map(|charge| charge.q);
+        let arrays = [DataArray::scalars(VTK_STATIONARY_ARRAYS[0].0, charges)];
+        vtk::save(file, |writer| {
+            vtk::write_polydata(writer, "Ładunki stacjonarne", &points, &arrays)
+        });
+        self.run_info
+            .write_sidecar(file, &VTK_STATIONARY_ARRAYS, Some(self.time_elapsed));
+    }
+
+    // the cells as a frame of the time series (.vti)
+    pub fn save_grid_frame(&self, file: &str) {
+        vtk::save(file, |writer| {
+            vtk::write_image_data(
+                writer,
+                (self.w, self.h),
+                self.cell_size,
+                &self.vtk_grid_arrays(),
+            )
+        });
+    }
+
+    // the movable charges as a frame of the time series (.vtp)
+    pub fn save_movable_charges_frame(&self, file: &str) {
+        let charges = || self.movable_charges.iter();
+        let points: Vec<XY<f64>> = charges()
+            .map(|charge| XY {
+                x: charge.x * self.cell_size,
+                y: charge.y * self.cell_size,
+            })
+            .collect();
+        let arrays = [
+            DataArray::scalars(VTK_MOVABLE_ARRAYS[0].0, charges().map(|c| c.id as f64)),
+            DataArray::scalars(VTK_MOVABLE_ARRAYS[1].0, charges().map(|c| c.q)),
+            DataArray::vectors(VTK_MOVABLE_ARRAYS[2].0, charges().map(|c| c.v)),
+            DataArray::scalars(
+                VTK_MOVABLE_ARRAYS[3].0,
+                charges().map(|c| movable_charge::lorentz_factor(c.v)),
+            ),
+            DataArray::scalars(
+                VTK_MOVABLE_ARRAYS[4].0,
+                charges().map(|c| c.kinetic_energy(self.relativistic)),
+            ),
+        ];
+        vtk::save(file, |writer| vtk::write_vertices(writer, &points, &arrays));
+    }
+
+    #[allow(dead_code)]
     fn display_intensity_color(&self) {
         for row in &self.cells {
             for cell in row {
@@ -179,6 +1139,7 @@ impl CellGrid {
         }
     }
 
+    #[allow(dead_code)]
     fn display_potential_color(&self) {
         for row in &self.cells {
             for cell in row {
@@ -188,92 +1149,505 @@ impl CellGrid {
         }
     }
 
-    pub fn save_movement_history(&self) {
-        // TODO make this work better with multiple charges
+    // The charge gets the next free ID, its movement history is kept under it.
+    pub fn add_movable_charge(&mut self, mut charge: MovableCharge) {
+        charge.id = self.next_charge_id;
+        self.next_charge_id += 1;
+        if let Some(trajectory) = &mut self.trajectory {
+            trajectory.add_charge(&charge);
+        }
+        self.movable_charges.push(charge);
+        self.accelerations_outdated = true;
+    }
 
-        // check if the movement history is enabled
-        if !self.track_movement {
-            panic!("Nie można zapisać historii ruchu, gdy opcja jest wyłączona!");
+    // writes the buffered rows of the trajectories to disk at the end of the
+    // run (and builds the VTK trajectories)
+    pub fn flush_trajectories(&mut self) {
+        if let Some(trajectory) = &mut self.trajectory {
+            trajectory.finish();
         }
+    }
 
-        for i in 0..self.movable_charges.len() {
-            // output/charge_[i].csv
-            let mut output_file = fs::File::create(format!("output/charge_{}.csv", i))
-                .expect("Nie można utworzyć pliku");
-            {
-                let mut output_file_buffer = BufWriter::new(&mut output_file);
-                for step in &self.movement_history[i] {
-                    writeln!(
-                        output_file_buffer,
-                        // write with 6 decimal places
-                        "{:.6}, {:.6}, {:.6}, {:.6}, {:.6}, {:.6}",
-                        step.x, step.y, step.v.x, step.v.y, step.a.x, step.a.y
-                    )
-                    .expect("Nie można zapisać do pliku");
+    // Counts the state of the charge `i` and writes it to its trajectory file
+    // (unless it's skipped by the decimation) with its potential energy.
+    fn write_trajectory(&mut self, i: usize, step: MovementStep) {
+        let write = match &mut self.trajectory {
+            Some(trajectory) => trajectory.record(self.movable_charges[i].id, step.event, step.dt),
+            None => false,
+        };
+        if !write {
+            return;
+        }
+        // NaN if the charge is too close to a source
+        let potential = self
+            .intensity_potential_at(step.x, step.y)
+            .map_or(f64::NAN, |(_, potential)| potential);
+        if let Some(trajectory) = &mut self.trajectory {
+            trajectory.write(
+                &self.movable_charges[i],
+                &step,
+                potential,
+                self.relativistic,
+            );
+        }
+    }
+
+    // records the current state of the charge at time t as a terminal row of its trajectory
+    fn record_event(&mut self, i: usize, event: MovementEvent, t: f64) {
+        let charge = &self.movable_charges[i];
+        let step = MovementStep {
+            t,
+            x: charge.x,
+            y: charge.y,
+            v: charge.v,
+            a: charge.a,
+            dt: 0.,
+            event,
+        };
+        self.write_trajectory(i, step);
+    }
+
+    // Accelerations of the charges in `group` (indices into movable_charges),
+    // placed at `positions`, both in grid units (cells and cells/s^2). Charges closer than the collision radius to
+    // each other (in N-body mode, unless softened) or too close to a stationary charge are
+    // reported as collided (indices within the group).
+    fn group_accelerations(
+        &self,
+        group: &[usize],
+        positions: &[XY<f64>],
+    ) -> Result<Vec<XY<f64>>, Vec<usize>> {
+        let mut collided = Vec::new();
+        // a [cells/s^2] = q * E / (m * cell_size)
+        let mut accelerations: Vec<XY<f64>> = group
+            .iter()
+            .zip(positions)
+            .enumerate()
+            .map(|(k, (&i, position))| {
+                let charge = &self.movable_charges[i];
+                match self.intensity_at(position.x, position.y) {
+                    Some(intensity) => intensity * (charge.q / (charge.m * self.cell_size)),
+                    None => {
+                        collided.push(k);
+                        XY::zero()
+                    }
+                }
+            })
+            .collect();
+
+        if self.interactions {
+            // every pair is visited once and the force is applied to both
+            // charges with opposite signs (Newton's third law)
+            for k in 0..group.len() {
+                for l in (k + 1)..group.len() {
+                    let (first, second) = (
+                        &self.movable_charges[group[k]],
+                        &self.movable_charges[group[l]],
+                    );
+                    let r = self.periods().wrap(positions[k] - positions[l]);
+                    let softening = self.softening();
+                    let r_length = (r.length().powi(2) + softening.powi(2)).sqrt();
+                    if softening == 0. && r_length < self.collision_radius {
+                        collided.push(k);
+                        collided.push(l);
+                        continue;
+                    }
+                    // force in newtons, the distance is converted to metres
+                    let force =
+                        r * (K * first.q * second.q / (r_length.powi(3) * self.cell_size.powi(2)));
+                    accelerations[k] += force * (1. / (first.m * self.cell_size));
+                    accelerations[l] += force * (-1. / (second.m * self.cell_size));
                 }
             }
-            // remove the last newline
-            output_file.flush().expect("Nie można wyczyścić bufora");
+        }
+
+        if collided.is_empty() {
+            Ok(accelerations)
+        } else {
+            collided.sort_unstable();
+            collided.dedup();
+            Err(collided)
         }
     }
 
-    pub fn add_movable_charge(&mut self, charge: MovableCharge) {
-        self.movable_charges.push(charge);
-        // add a new vector to the movement history
-        self.movement_history.push(Vec::new());
+    // cyclotron frequencies q Bz / m [rad/s] of the charges in `group` placed at `positions`
+    fn group_gyration(&self, group: &[usize], positions: &[XY<f64>]) -> Vec<f64> {
+        group
+            .iter()
+            .zip(positions)
+            .map(|(&i, position)| {
+                let charge = &self.movable_charges[i];
+                charge.q * self.magnetic_field_at(position.x, position.y) / charge.m
+            })
+            .collect()
     }
 
-    pub fn update_movable_charges(&mut self, delta_t: f64) {
-        for (i, movable_charge) in &mut self
-            .movable_charges
-            .iter_mut()
-            .filter(|c| c.should_move)
-            .enumerate()
-        {
-            // TODO popraw żeby raz używało prawidłowo wczesniejszych wartości raz aktualnych
-            let intensity = field_intensity_movable(
-                movable_charge.x,
-                movable_charge.y,
-                &self.stationary_charges,
-            );
-            // if the charge is too close to a stationary charge, field_intensity_movable returns Inf for all values
-            // thus why we check only one of them
-            // in that case, we don't want to update the charge's position
-            if intensity.is_none() {
-                movable_charge.collided = true;
-                movable_charge.should_move = false;
-                continue;
+    // speed of light in cells/s, only in the relativistic mode
+    fn light_speed(&self) -> Option<f64> {
+        self.relativistic.then(|| SPEED_OF_LIGHT / self.cell_size)
+    }
+
+    // Converts the velocity of a charge [m/s] to the velocity used by the
+    // integrators (cells/s), which is the momentum per unit mass γv in the
+    // relativistic mode. Speeds that aren't below c are capped first.
+    fn state_velocity(&self, v: XY<f64>) -> XY<f64> {
+        let v = v * (1. / self.cell_size);
+        let Some(c) = self.light_speed() else {
+            return v;
+        };
+        let max_speed = c * MAX_SPEED_FRACTION;
+        let v = if v.length() > max_speed {
+            v * (max_speed / v.length())
+        } else {
+            v
+        };
+        v * (1. / (1. - (v.length() / c).powi(2)).sqrt())
+    }
+
+    // inverse of state_velocity, returns the velocity in m/s
+    fn charge_velocity(&self, u: XY<f64>) -> XY<f64> {
+        let v = match self.light_speed() {
+            Some(c) => u * (1. / (1. + (u.length() / c).powi(2)).sqrt()),
+            None => u,
+        };
+        v * self.cell_size
+    }
+
+    // the forces acting on the group, the magnetic part is skipped when there is no field
+    fn with_group_forces<T>(&self, group: &[usize], f: impl FnOnce(&Forces) -> T) -> T {
+        let electric = |positions: &[XY<f64>]| self.group_accelerations(group, positions);
+        let gyration = |positions: &[XY<f64>]| self.group_gyration(group, positions);
+        f(&Forces {
+            electric: &electric,
+            gyration: if self.magnetic_fields.is_empty() {
+                None
+            } else {
+                Some(&gyration)
+            },
+            light_speed: self.light_speed(),
+        })
+    }
+
+    // indices of the charges that are integrated together, all moving charges
+    // form a single group in N-body mode
+    fn movement_groups(&self) -> Vec<Vec<usize>> {
+        let moving =
+            (0..self.movable_charges.len()).filter(|&i| self.movable_charges[i].should_move);
+        if self.interactions {
+            vec![moving.collect()]
+        } else {
+            moving.map(|i| vec![i]).collect()
+        }
+    }
+
+    // recomputes the cached accelerations, so that they match the current positions
+    fn refresh_accelerations(&mut self) {
+        for group in self.movement_groups() {
+            let positions: Vec<XY<f64>> = group
+                .iter()
+                .map(|&i| XY {
+                    x: self.movable_charges[i].x,
+                    y: self.movable_charges[i].y,
+                })
+                .collect();
+            let velocities: Vec<XY<f64>> = group
+                .iter()
+                .map(|&i| self.state_velocity(self.movable_charges[i].v))
+                .collect();
+            // collisions are detected during the next step, so they are ignored here
+            let accelerations = self.with_group_forces(&group, |forces| {
+                forces.acceleration(&positions, &velocities)
+            });
+            if let Ok(accelerations) = accelerations {
+                for (&i, a) in group.iter().zip(accelerations) {
+                    self.movable_charges[i].a = a * self.cell_size;
+                }
             }
-            let intensity = intensity.unwrap();
+        }
+        self.accelerations_outdated = false;
+    }
 
-            movable_charge.x +=
-                (movable_charge.v.x * delta_t) + (0.5 * movable_charge.a.x * delta_t.powi(2));
-            movable_charge.y +=
-                (movable_charge.v.y * delta_t) + (0.5 * movable_charge.a.y * delta_t.powi(2));
+    // Integrates the group over delta_t without modifying the grid, so that
+    // independent groups can be integrated in parallel. Returns the final state,
+    // the recorded intermediate states (if movement is tracked) and the suggested
+    // adaptive step, or the indices (within the group) of the collided charges.
+    fn integrate_group(
+        &self,
+        group: &[usize],
+        delta_t: f64,
+        integrator: &dyn Integrator,
+    ) -> GroupResult {
+        let mut state = State {
+            x: group
+                .iter()
+                .map(|&i| XY {
+                    x: self.movable_charges[i].x,
+                    y: self.movable_charges[i].y,
+                })
+                .collect(),
+            // the charges store velocities and accelerations in SI units,
+            // the integrators work in grid units (cells/s and cells/s^2)
+            v: group
+                .iter()
+                .map(|&i| self.state_velocity(self.movable_charges[i].v))
+                .collect(),
+            a: group
+                .iter()
+                .map(|&i| self.movable_charges[i].a * (1. / self.cell_size))
+                .collect(),
+        };
+        let mut suggested_dt = group
+            .iter()
+            .map(|&i| self.movable_charges[i].adaptive_dt)
+            .fold(f64::INFINITY, f64::min);
+        let mut steps = Vec::new();
+        let track_movement = self.trajectory.is_some();
 
-            movable_charge.v.x += movable_charge.a.x * delta_t;
-            movable_charge.v.y += movable_charge.a.y * delta_t;
+        self.with_group_forces(group, |forces| {
+            integrator.advance(
+                &mut state,
+                delta_t,
+                &mut suggested_dt,
+                forces,
+                &mut |state, dt| {
+                    if track_movement {
+                        steps.push((state.clone(), dt));
+                    }
+                },
+            )
+        })?;
+        Ok((state, steps, suggested_dt))
+    }
 
-            movable_charge.a.x = intensity.x * movable_charge.q / movable_charge.m;
-            movable_charge.a.y = intensity.y * movable_charge.q / movable_charge.m;
+    // Writes the result of integrate_group back to the charges. Collided charges
+    // are handled according to the collision model (their position is not
+    // updated), returns the charges of the group that still have to be
+    // integrated over this step: the rest of the group and the bounced charges.
+    // `bounced` holds a charge once for every reflection during this step. A
+    // charge that can't be reflected (or has bounced MAX_BOUNCES times) keeps
+    // its position and velocity until the next step.
+    fn apply_group_result(
+        &mut self,
+        group: Vec<usize>,
+        result: GroupResult,
+        delta_t: f64,
+        bounced: &mut Vec<usize>,
+    ) -> Vec<usize> {
+        match result {
+            Ok((state, steps, suggested_dt)) => {
+                for (k, &i) in group.iter().enumerate() {
+                    let v = self.charge_velocity(state.v[k]);
+                    let charge = &mut self.movable_charges[i];
+                    charge.x = state.x[k].x;
+                    charge.y = state.x[k].y;
+                    charge.v = v;
+                    charge.a = state.a[k] * self.cell_size;
+                    charge.adaptive_dt = suggested_dt;
+                }
+                // time of the recorded states, the step starts at time_elapsed
+                let mut t = self.time_elapsed;
+                for (step_state, dt) in steps {
+                    t += dt;
+                    for (k, &i) in group.iter().enumerate() {
+                        let step = MovementStep {
+                            t,
+                            x: step_state.x[k].x,
+                            y: step_state.x[k].y,
+                            v: self.charge_velocity(step_state.v[k]),
+                            a: step_state.a[k] * self.cell_size,
+                            dt,
+                            event: MovementEvent::Step,
+                        };
+                        self.write_trajectory(i, step);
+                    }
+                }
+                let mut moved = false;
+                for &i in &group {
+                    moved |= self.apply_boundaries(i);
+                    if self.movable_charges[i].escaped {
+                        self.record_event(i, MovementEvent::Escape, self.time_elapsed + delta_t);
+                    }
+                }
+                // the cached accelerations were computed at the old positions
+                // (or, for absorbed charges, include them in N-body mode)
+                if moved || group.iter().any(|&i| !self.movable_charges[i].should_move) {
+                    self.accelerations_outdated = true;
+                }
+                Vec::new()
+            }
+            Err(collided) => {
+                let mut stopped = Vec::new();
+                let mut held = Vec::new();
+                for &k in &collided {
+                    let i = group[k];
+                    let target = self.collision_target(i, delta_t);
+                    match (self.collision_model, target) {
+                        (CollisionModel::Absorb, Some(target)) => {
+                            let q = self.movable_charges[i].q;
+                            self.absorb_charge(target, q);
+                            self.record_event(i, MovementEvent::Absorption, self.time_elapsed);
+                            stopped.push(k);
+                        }
+                        (CollisionModel::Bounce, Some(_)) => {
+                            let bounces = bounced.iter().filter(|&&j| j == i).count();
+                            if bounces < MAX_BOUNCES && self.reflect_velocity(i, delta_t) {
+                                bounced.push(i);
+                            } else {
+                                held.push(k);
+                            }
+                        }
+                        _ => {
+                            self.record_event(i, MovementEvent::Collision, self.time_elapsed);
+                            stopped.push(k);
+                        }
+                    }
+                }
+                for &k in &stopped {
+                    let charge = &mut self.movable_charges[group[k]];
+                    charge.collided = true;
+                    charge.should_move = false;
+                }
+                // the stopped charges no longer act on the rest of the group
+                if self.interactions {
+                    self.refresh_accelerations();
+                }
+                group
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(k, _)| !stopped.contains(k) && !held.contains(k))
+                    .map(|(_, i)| i)
+                    .collect()
+            }
+        }
+    }
 
-            // println!(
-            //     "x: {},\ty: {},\tv: {:?},\ta: {:?}",
-            //     movable_charge.x, movable_charge.y, movable_charge.v, movable_charge.a
-            // );
+    pub fn update_movable_charges(&mut self, delta_t: f64) {
+        if self.interactions != self.last_interactions
+            || self.field_lookup != self.last_field_lookup
+        {
+            self.last_interactions = self.interactions;
+            self.last_field_lookup = self.field_lookup;
+            self.accelerations_outdated = true;
+        }
+        // The time-varying charges are held at their values in the middle of
+        // the step. The field interpolated from the cells has to follow them
+        // at every step, otherwise the cells are only marked as outdated.
+        if self.update_stationary_charges(self.time_elapsed + delta_t / 2.) {
+            if self.field_lookup != FieldLookup::Direct {
+                self.refresh_field();
+            } else {
+                self.field_outdated = true;
+                self.accelerations_outdated = true;
+            }
+        }
+        if self.accelerations_outdated {
+            self.refresh_accelerations();
+        }
 
-            if self.track_movement {
-                self.movement_history[i].push(MovementStep {
-                    x: movable_charge.x,
-                    y: movable_charge.y,
-                    a: movable_charge.a,
-                    v: movable_charge.v,
-                });
+        let integrator = self.integrator.integrator(self.tolerance);
+        let mut groups = self.movement_groups();
+        let mut bounced = Vec::new();
+
+        // Independent groups are integrated in parallel, the results are applied
+        // in the original order so the output is deterministic. In N-body mode
+        // there is a single group with all of the charges. Groups with collided
+        // charges are integrated again without them (or with the bounced ones).
+        while !groups.is_empty() {
+            let results: Vec<GroupResult> = groups
+                .par_iter()
+                .map(|group| self.integrate_group(group, delta_t, integrator.as_ref()))
+                .collect();
+            groups = groups
+                .into_iter()
+                .zip(results)
+                .map(|(group, result)| {
+                    self.apply_group_result(group, result, delta_t, &mut bounced)
+                })
+                .filter(|group| !group.is_empty())
+                .collect();
+        }
+        self.time_elapsed += delta_t;
+    }
+
+    // total (kinetic + potential) energy of the charges that are still moving,
+    // used to compare the energy drift of different integrators
+    pub fn total_energy(&self) -> f64 {
+        let moving: Vec<&MovableCharge> = self
+            .movable_charges
+            .iter()
+            .filter(|c| c.should_move)
+            .collect();
+        let mut energy: f64 = moving
+            .iter()
+            .map(|c| {
+                let kinetic = c.kinetic_energy(self.relativistic);
+                let potential = self
+                    .intensity_potential_at(c.x, c.y)
+                    .map_or(0., |(_, potential)| potential);
+                kinetic + c.q * potential
+            })
+            .sum();
+        // potential energy of every pair of movable charges
+        if self.interactions {
+            for (k, first) in moving.iter().enumerate() {
+                for second in &moving[(k + 1)..] {
+                    let r = self
+                        .periods()
+                        .wrap(XY {
+                            x: first.x - second.x,
+                            y: first.y - second.y,
+                        })
+                        .length();
+                    let r = (r.powi(2) + self.softening().powi(2)).sqrt();
+                    energy += K * first.q * second.q / (r * self.cell_size);
+                }
             }
         }
+        energy
     }
 }
 
+// whether (x, y) (in cells) is closer than `distance` to a stationary charge or distribution
+fn near_source(
+    stationary_charges: &[StationaryCharge],
+    charge_distributions: &[ChargeDistribution],
+    x: f64,
+    y: f64,
+    distance: f64,
+    periods: Periods,
+) -> bool {
+    stationary_charges.iter().any(|c| {
+        let d = periods.wrap(XY {
+            x: c.x - x,
+            y: c.y - y,
+        });
+        d.x.powi(2) + d.y.powi(2) < distance.powi(2)
+    }) || charge_distributions
+        .iter()
+        .any(|distribution| distribution.distance_to(x, y) < distance)
+}
+
+// Field intensity [V/m] and potential [V] at (x, y) (in cells) of the sources
+// other than the stationary point charges: the external fields and the charge
+// distributions. None if the point is closer than `min_distance` to a distribution.
+fn extra_field(
+    external_fields: &[ExternalField],
+    charge_distributions: &[ChargeDistribution],
+    x: f64,
+    y: f64,
+    cell_size: f64,
+    min_distance: f64,
+) -> Option<(XY<f64>, f64)> {
+    let (mut intensity, mut potential) =
+        external_intensity_potential(external_fields, x, y, cell_size);
+    for distribution in charge_distributions {
+        let (distribution_intensity, distribution_potential) =
+            distribution.field_at(x, y, cell_size, min_distance)?;
+        intensity += distribution_intensity;
+        potential += distribution_potential;
+    }
+    Some((intensity, potential))
+}
+
 // function used to calculate the field intensity and potential generated by
 // stationary charges at a given point (x, y)
 // used for displaying the background, and for calculating the field intensity
@@ -283,28 +1657,33 @@ fn field_intensity_potential(
     stationary_charges: &[StationaryCharge],
     x: usize,
     y: usize,
+    softening: f64,
+    periods: Periods,
 ) -> CellData {
     let mut intensity = XY { x: 0.0, y: 0.0 };
     let mut potential = 0.0;
     for stationary_charge in stationary_charges {
-        let r_sq = ((x as i32 - stationary_charge.x as i32).pow(2)
-            + (y as i32 - stationary_charge.y as i32).pow(2)) as f64;
+        let XY { x: dx, y: dy } = periods.wrap(XY {
+            x: x as f64 - stationary_charge.x,
+            y: y as f64 - stationary_charge.y,
+        });
+        let r_sq = dx.powi(2) + dy.powi(2) + softening.powi(2);
         let r = r_sq.sqrt();
 
         if r == 0.0 {
             return CellData {
                 intensity: XY {
-                    x: INFINITY,
-                    y: INFINITY,
+                    x: f64::INFINITY,
+                    y: f64::INFINITY,
                 },
-                potential: INFINITY,
+                potential: f64::INFINITY,
             };
         }
 
         let factor = K * stationary_charge.q / (r_sq * r);
 
-        intensity.x += factor * (x as i32 - stationary_charge.x as i32) as f64;
-        intensity.y += factor * (y as i32 - stationary_charge.y as i32) as f64;
+        intensity.x += factor * dx;
+        intensity.y += factor * dy;
         potential += K * stationary_charge.q / r;
     }
     CellData {
@@ -312,3 +1691,510 @@ fn field_intensity_potential(
         potential,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output_format::OutputFormat;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    fn movable(x: f64, y: f64, q: f64, m: f64, vx: f64, vy: f64) -> MovableCharge {
+        MovableCharge {
+            id: 0,
+            name: None,
+            should_move: true,
+            collided: false,
+            escaped: false,
+            x,
+            y,
+            q,
+            m,
+            v: XY { x: vx, y: vy },
+            a: XY::zero(),
+            adaptive_dt: 0.,
+        }
+    }
+
+    // charges of both signs and different masses, far enough from each other
+    // not to collide, without any stationary charges
+    fn interacting_grid() -> CellGrid {
+        let mut grid = CellGrid::new(64, 64, 1.);
+        grid.interactions = true;
+        for charge in [
+            movable(10., 10., 1e-9, 1e-10, 0., 0.),
+            movable(30., 12., -2e-9, 3e-10, 5., -1.),
+            movable(20., 40., 1e-9, 2e-10, -2., 3.),
+            movable(45., 30., -1e-9, 1e-10, 0., 1.),
+        ] {
+            grid.add_movable_charge(charge);
+        }
+        grid
+    }
+
+    fn total_momentum(grid: &CellGrid) -> XY<f64> {
+        grid.movable_charges
+            .iter()
+            .fold(XY::zero(), |sum, c| sum + c.v * c.m)
+    }
+
+    #[test]
+    fn pairwise_forces_sum_to_zero() {
+        let grid = interacting_grid();
+        let group: Vec<usize> = (0..grid.movable_charges.len()).collect();
+        let positions: Vec<XY<f64>> = grid
+            .movable_charges
+            .iter()
+            .map(|c| XY { x: c.x, y: c.y })
+            .collect();
+        let accelerations = grid.group_accelerations(&group, &positions).unwrap();
+
+        let mut sum = XY::zero();
+        let mut largest: f64 = 0.;
+        for (charge, a) in grid.movable_charges.iter().zip(&accelerations) {
+            let force = *a * charge.m;
+            sum += force;
+            largest = largest.max(force.length());
+        }
+        assert!(largest > 0.);
+        assert!(sum.length() < largest * 1e-12, "{} {}", sum.x, sum.y);
+    }
+
+    #[test]
+    fn interactions_conserve_momentum() {
+        for kind in IntegratorKind::ALL {
+            let mut grid = interacting_grid();
+            grid.integrator = kind;
+            let initial = total_momentum(&grid);
+            let scale = grid
+                .movable_charges
+                .iter()
+                .map(|c| (c.v * c.m).length())
+                .sum::<f64>();
+
+            for _ in 0..1000 {
+                grid.update_movable_charges(1e-3);
+            }
+            assert!(grid.movable_charges.iter().all(|c| !c.collided));
+            let drift = (total_momentum(&grid) - initial).length();
+            assert!(drift < scale * 1e-12, "{}: {:e}", kind.name(), drift);
+        }
+    }
+
+    // a few hundred positive charges of random magnitude filling a strip along
+    // the left edge, so the field doesn't cancel out anywhere in the rest of the
+    // grid (the relative error is meaningless where it does)
+    fn random_stationary_grid(theta: f64) -> CellGrid {
+        let mut rng = ChaCha8Rng::seed_from_u64(4);
+        let mut grid = CellGrid::new(64, 64, 1.);
+        for x in 0..5 {
+            for y in 0..64 {
+                grid.stationary_charges.push(StationaryCharge {
+                    x: x as f64,
+                    y: y as f64,
+                    q: rng.gen_range(1e-10..1e-9),
+                    schedule: None,
+                });
+            }
+        }
+        grid.use_barnes_hut(theta);
+        grid
+    }
+
+    #[test]
+    fn barnes_hut_error_is_bounded() {
+        let (intensity_error, potential_error) =
+            random_stationary_grid(0.5).barnes_hut_error().unwrap();
+        assert!(intensity_error < 0.05, "natężenie {:e}", intensity_error);
+        assert!(potential_error < 0.02, "potencjał {:e}", potential_error);
+    }
+
+    #[test]
+    fn barnes_hut_without_opening_angle_matches_direct_sum() {
+        // only the order of the summation differs
+        let (intensity_error, potential_error) =
+            random_stationary_grid(0.).barnes_hut_error().unwrap();
+        assert!(intensity_error < 1e-12, "natężenie {:e}", intensity_error);
+        assert!(potential_error < 1e-12, "potencjał {:e}", potential_error);
+    }
+
+    // the same scenario simulated in a pool with the given number of threads
+    fn simulate_in_pool(threads: usize) -> CellGrid {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        pool.install(|| {
+            let mut rng = ChaCha8Rng::seed_from_u64(5);
+            let mut grid = CellGrid::new(64, 64, 1.);
+            for _ in 0..20 {
+                grid.stationary_charges.push(StationaryCharge {
+                    x: rng.gen_range(0. ..64.),
+                    y: rng.gen_range(0. ..64.),
+                    q: rng.gen_range(-1e-9..1e-9),
+                    schedule: None,
+                });
+            }
+            for _ in 0..50 {
+                grid.add_movable_charge(movable(
+                    rng.gen_range(0. ..64.),
+                    rng.gen_range(0. ..64.),
+                    rng.gen_range(-1e-9..1e-9),
+                    1e-10,
+                    rng.gen_range(-5. ..5.),
+                    rng.gen_range(-5. ..5.),
+                ));
+            }
+            grid.populate_field();
+            for _ in 0..200 {
+                grid.update_movable_charges(1e-2);
+            }
+            grid
+        })
+    }
+
+    #[test]
+    fn results_do_not_depend_on_thread_count() {
+        let single = simulate_in_pool(1);
+        let parallel = simulate_in_pool(4);
+
+        let cell_bits = |grid: &CellGrid| -> Vec<u64> {
+            grid.cells
+                .iter()
+                .flatten()
+                .flat_map(|cell| [cell.e.x.to_bits(), cell.e.y.to_bits(), cell.v.to_bits()])
+                .collect()
+        };
+        assert!(cell_bits(&single) == cell_bits(&parallel));
+
+        let charge_bits = |grid: &CellGrid| -> Vec<(u64, u64, u64, u64, bool)> {
+            grid.movable_charges
+                .iter()
+                .map(|c| {
+                    (
+                        c.x.to_bits(),
+                        c.y.to_bits(),
+                        c.v.x.to_bits(),
+                        c.v.y.to_bits(),
+                        c.collided,
+                    )
+                })
+                .collect()
+        };
+        assert!(charge_bits(&single) == charge_bits(&parallel));
+        // some of the charges should still be moving for the comparison to matter
+        assert!(single.movable_charges.iter().any(|c| c.should_move));
+    }
+
+    // A charge falling into an opposite source, one flying past a like one and
+    // one pushed away from it at rest, moved by the direct sums and by the
+    // interpolation of the cells
+    #[test]
+    fn interpolated_trajectories_follow_direct() {
+        let grid_with = |lookup: FieldLookup| {
+            let mut grid = CellGrid::new(64, 64, 1.);
+            grid.add_stationary_charge(StationaryCharge {
+                x: 32.,
+                y: 32.,
+                q: 2e-9,
+                schedule: None,
+            });
+            for charge in [
+                movable(32., 16., -5e-9, 1e-19, 0., 0.),
+                movable(4., 40., 5e-9, 1e-19, 1e5, 0.),
+                movable(44., 44., 5e-9, 1e-19, 0., 0.),
+            ] {
+                grid.add_movable_charge(charge);
+            }
+            grid.field_lookup = lookup;
+            grid.populate_field();
+            grid
+        };
+        let delta_t = 1e-7;
+
+        for lookup in [FieldLookup::Bilinear, FieldLookup::Bicubic] {
+            let mut direct = grid_with(FieldLookup::Direct);
+            let mut interpolated = grid_with(lookup);
+
+            let mut max_deviation = 0f64;
+            for _ in 0..3000 {
+                direct.update_movable_charges(delta_t);
+                interpolated.update_movable_charges(delta_t);
+                for (a, b) in direct
+                    .movable_charges
+                    .iter()
+                    .zip(&interpolated.movable_charges)
+                {
+                    let deviation = ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt();
+                    max_deviation = max_deviation.max(deviation);
+                }
+            }
+            // the interpolation error is largest next to the source, the paths
+            // stay within a quarter of a cell of each other
+            assert!(
+                max_deviation < 0.25,
+                "{}: odchylenie {} komórek",
+                lookup.name(),
+                max_deviation
+            );
+
+            let collided = |grid: &CellGrid| -> Vec<bool> {
+                grid.movable_charges.iter().map(|c| c.collided).collect()
+            };
+            assert_eq!(collided(&direct), [true, false, false]);
+            assert_eq!(collided(&interpolated), collided(&direct));
+        }
+    }
+
+    #[test]
+    fn periods_wrap_gives_minimum_image() {
+        let periods = Periods {
+            x: Some(10.),
+            y: None,
+        };
+        for (d, image) in [(3., 3.), (6., -4.), (-7., 3.), (23., 3.), (-36., 4.)] {
+            let wrapped = periods.wrap(XY { x: d, y: d });
+            assert!((wrapped.x - image).abs() < 1e-12, "{} -> {}", d, wrapped.x);
+            // no period along y
+            assert_eq!(wrapped.y, d);
+        }
+    }
+
+    // Edges at -0.5 and 9.5 of a 10 x 10 grid, positions several widths outside
+    // of them are folded back the same way as reflecting or wrapping the charge
+    // one crossing at a time
+    #[test]
+    fn boundaries_fold_positions_several_periods_out() {
+        let (min, max) = (-0.5, 9.5);
+        for x in [-47.3, -0.6, 9.7, 32.5, 71.2] {
+            let (mut expected_x, mut expected_v) = (x, 1.);
+            while !(min..=max).contains(&expected_x) {
+                expected_x = if expected_x < min {
+                    2. * min - expected_x
+                } else {
+                    2. * max - expected_x
+                };
+                expected_v = -expected_v;
+            }
+
+            let mut grid = CellGrid::new(10, 10, 1.);
+            grid.boundaries = Boundaries::all(Boundary::Reflect);
+            grid.add_movable_charge(movable(x, 5., 1e-9, 1e-10, 1., 0.));
+            assert!(grid.apply_boundaries(0));
+            let charge = &grid.movable_charges[0];
+            assert!((charge.x - expected_x).abs() < 1e-9, "{}: {}", x, charge.x);
+            assert_eq!(charge.v.x, expected_v);
+            assert_eq!((charge.y, charge.v.y), (5., 0.));
+
+            grid.boundaries = Boundaries::all(Boundary::Periodic);
+            grid.movable_charges[0].x = x;
+            assert!(grid.apply_boundaries(0));
+            let wrapped = grid.movable_charges[0].x;
+            assert!((min..max).contains(&wrapped), "{}: {}", x, wrapped);
+            let shift = (wrapped - x) / 10.;
+            assert!((shift - shift.round()).abs() < 1e-9, "{}: {}", x, wrapped);
+        }
+    }
+
+    #[test]
+    fn absorbed_charge_is_counted_once() {
+        let mut grid = CellGrid::new(10, 10, 1.);
+        grid.boundaries = Boundaries::all(Boundary::Absorb);
+        // leaving through the corner, across two absorbing edges at once
+        grid.add_movable_charge(movable(8., 8., 1e-9, 1e-10, 3e5, 3e5));
+        grid.add_movable_charge(movable(5., 5., 1e-9, 1e-10, 0., 0.));
+        let escaped = |grid: &CellGrid| grid.movable_charges.iter().filter(|c| c.escaped).count();
+
+        grid.update_movable_charges(1e-5);
+        let charge = &grid.movable_charges[0];
+        assert!(!charge.should_move && charge.x > 9.5 && charge.y > 9.5);
+        assert_eq!(escaped(&grid), 1);
+
+        // the absorbed charge stays where it stopped
+        let position = (charge.x, charge.y);
+        for _ in 0..10 {
+            grid.update_movable_charges(1e-5);
+        }
+        let charge = &grid.movable_charges[0];
+        assert_eq!((charge.x, charge.y), position);
+        assert_eq!(escaped(&grid), 1);
+
+        // a reflecting edge stops a charge that can't be brought back
+        grid.boundaries = Boundaries::all(Boundary::Reflect);
+        grid.movable_charges[1].x = f64::INFINITY;
+        grid.apply_boundaries(1);
+        assert!(!grid.movable_charges[1].should_move);
+        assert_eq!(escaped(&grid), 2);
+    }
+
+    // a grid with stationary charges on the line y = 32 and the collision model
+    fn collision_grid(model: CollisionModel, sources: &[(f64, f64)]) -> CellGrid {
+        let mut grid = CellGrid::new(64, 64, 1.);
+        grid.collision_model = model;
+        for &(x, q) in sources {
+            grid.add_stationary_charge(StationaryCharge {
+                x,
+                y: 32.,
+                q,
+                schedule: None,
+            });
+        }
+        grid
+    }
+
+    #[test]
+    fn absorbed_charge_is_added_to_the_source() {
+        let mut grid = collision_grid(CollisionModel::Absorb, &[(32., 2e-9)]);
+        grid.add_movable_charge(movable(20., 32., -5e-9, 1e-19, 0., 0.));
+        for _ in 0..2000 {
+            grid.update_movable_charges(1e-7);
+        }
+        let charge = &grid.movable_charges[0];
+        assert!(charge.collided && !charge.should_move);
+        assert!((grid.stationary_charges[0].q + 3e-9).abs() < 1e-24);
+        assert!((grid.cells[32][32].q + 3e-9).abs() < 1e-24);
+    }
+
+    // Two sources with a negligible field, the charge between them is reflected
+    // from one to the other at every contact, keeping its speed
+    #[test]
+    fn bounced_charge_is_reflected_at_every_contact() {
+        let mut grid = collision_grid(CollisionModel::Bounce, &[(12., 1e-30), (32., 1e-30)]);
+        grid.add_movable_charge(movable(22., 32., 1e-9, 1e-10, 1e5, 0.));
+        let mut reflections = 0;
+        let mut last_vx = 1e5;
+        for _ in 0..1000 {
+            grid.update_movable_charges(1e-6);
+            let charge = &grid.movable_charges[0];
+            assert!(charge.should_move && !charge.collided);
+            assert!((14. ..=30.).contains(&charge.x), "x = {}", charge.x);
+            assert!((charge.v.length() / 1e5 - 1.).abs() < 1e-9);
+            if charge.v.x * last_vx < 0. {
+                reflections += 1;
+            }
+            last_vx = charge.v.x;
+        }
+        // 0.1 cell per step, 16 cells between the spheres
+        assert!(reflections >= 5, "{} odbić", reflections);
+    }
+
+    // The charge moves away from the nearest source and into another one, then
+    // is reflected into the first one within the same step and reflected
+    // again, coming out between them
+    #[test]
+    fn repeated_contact_is_reflected_again() {
+        let mut grid = collision_grid(CollisionModel::Bounce, &[]);
+        for (x, y) in [(28.5, 30.6), (32.9, 32.6)] {
+            grid.add_stationary_charge(StationaryCharge {
+                x,
+                y,
+                q: 1e-30,
+                schedule: None,
+            });
+        }
+        grid.add_movable_charge(movable(30., 32., 1e-9, 1e-10, 1e5, 0.));
+        // a cell per step
+        grid.update_movable_charges(1e-5);
+        let charge = &grid.movable_charges[0];
+        assert!(charge.should_move && !charge.collided);
+        assert!(charge.v.x > 0. && charge.v.y > 0.8e5, "v = {:?}", charge.v);
+        assert!((charge.v.length() / 1e5 - 1.).abs() < 1e-9);
+
+        let v = charge.v;
+        for _ in 0..5 {
+            grid.update_movable_charges(1e-5);
+        }
+        let charge = &grid.movable_charges[0];
+        assert!(charge.should_move && !charge.collided);
+        assert_eq!((charge.v.x, charge.v.y), (v.x, v.y));
+    }
+
+    // An attracted charge falls back onto the source after every bounce, it
+    // is never stopped and never gets closer than the collision radius
+    #[test]
+    fn bounced_charge_keeps_moving_when_attracted() {
+        let mut grid = collision_grid(CollisionModel::Bounce, &[(32., 2e-9)]);
+        grid.add_movable_charge(movable(20., 32., -5e-9, 1e-19, 0., 0.));
+        let mut bounced = false;
+        for _ in 0..5000 {
+            grid.update_movable_charges(1e-7);
+            let charge = &grid.movable_charges[0];
+            assert!(charge.should_move && !charge.collided);
+            let distance = (charge.x - 32.).abs();
+            assert!((2. ..=12. + 1e-6).contains(&distance), "x = {}", charge.x);
+            bounced |= charge.v.x < 0.;
+        }
+        assert!(bounced);
+    }
+
+    // With the softened field a charge falls through the source and comes out
+    // on the other side, as far as it started
+    #[test]
+    fn softened_charge_passes_through_the_source() {
+        let mut grid = collision_grid(CollisionModel::Soften, &[(32., 2e-9)]);
+        grid.add_movable_charge(movable(20., 32., -5e-9, 1e-19, 0., 0.));
+        let intensity = grid.intensity_at(32., 32.).unwrap();
+        assert!(intensity.length() < 1e-9);
+
+        let mut max_x: f64 = 0.;
+        for _ in 0..2000 {
+            grid.update_movable_charges(1e-7);
+            let charge = &grid.movable_charges[0];
+            assert!(charge.should_move && !charge.collided);
+            max_x = max_x.max(charge.x);
+        }
+        assert!((max_x - 44.).abs() < 0.05, "x = {}", max_x);
+    }
+
+    // One charge collides with a source early, another one later leaves the
+    // grid: each trajectory file ends with a single terminal row, under the ID
+    // the charge got when it was added
+    #[test]
+    fn terminal_events_are_recorded_once() {
+        let directory = std::env::temp_dir().join("fizyka-projekt-zdarzenia");
+        fs::create_dir_all(&directory).unwrap();
+        let mut grid = CellGrid::new(64, 64, 1.);
+        let run_info = RunInfo::from_scenario(&Scenario::default());
+        grid.trajectory = Some(TrajectoryWriter::new(
+            directory.to_str().unwrap(),
+            1,
+            OutputFormat::Csv,
+            run_info,
+        ));
+        grid.boundaries = Boundaries::all(Boundary::Absorb);
+        grid.add_stationary_charge(StationaryCharge {
+            x: 32.,
+            y: 32.,
+            q: 2e-9,
+            schedule: None,
+        });
+        grid.add_movable_charge(movable(32., 26., -5e-9, 1e-19, 0., 0.));
+        grid.add_movable_charge(movable(50., 10., 1e-12, 1e-10, 2e5, 0.));
+        let ids: Vec<usize> = grid.movable_charges.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [0, 1]);
+
+        for _ in 0..1000 {
+            grid.update_movable_charges(1e-7);
+        }
+        grid.flush_trajectories();
+        let rows = |id: usize| -> Vec<String> {
+            let path = directory.join(format!("charge_{}.csv", id));
+            let contents = fs::read_to_string(path).unwrap();
+            let rows = contents.lines().skip(1);
+            rows.map(|row| row.to_string()).collect()
+        };
+        let events = |rows: &[String]| -> Vec<String> {
+            rows.iter()
+                .map(|row| row.rsplit(", ").next().unwrap().to_string())
+                .filter(|event| event != "krok")
+                .collect()
+        };
+        let (first, second) = (rows(0), rows(1));
+        assert_eq!(events(&first), ["zderzenie"]);
+        assert_eq!(events(&second), ["ucieczka"]);
+        assert!(first.last().unwrap().ends_with("zderzenie"));
+        assert!(second.last().unwrap().ends_with("ucieczka"));
+        // the first charge stopped long before the second one
+        assert!(first.len() * 2 < second.len());
+    }
+}
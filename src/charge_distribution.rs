@@ -0,0 +1,277 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lib::helpers::{K, XY};
+
+// Extended stationary field sources, so a charged wire or plate doesn't have to
+// be built from hundreds of point charges. Positions are given in cells, the
+// linear density in C/m and the surface density in C/m². The field is computed
+// analytically for line segments and rectangles, arcs are split into short
+// chords. In the scenario file every source is a [[ladunki_rozciagle]] table
+// with `typ` selecting the shape.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "typ", deny_unknown_fields)]
+pub enum ChargeDistribution {
+    #[serde(rename = "odcinek")]
+    Line {
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+        lambda: f64,
+    },
+    // arc of a circle with the center (x, y) and radius r, between the angles
+    // (in degrees, measured from the x axis), the whole ring by default
+    #[serde(rename = "luk")]
+    Arc {
+        x: f64,
+        y: f64,
+        r: f64,
+        #[serde(default, rename = "kat_poczatkowy")]
+        start_angle: f64,
+        #[serde(default = "full_angle", rename = "kat_koncowy")]
+        end_angle: f64,
+        lambda: f64,
+    },
+    #[serde(rename = "prostokat")]
+    Rectangle {
+        x_min: f64,
+        x_max: f64,
+        y_min: f64,
+        y_max: f64,
+        sigma: f64,
+    },
+}
+
+fn full_angle() -> f64 {
+    360.
+}
+
+// number of chords used for a whole ring
+const RING_SEGMENTS: f64 = 128.;
+
+// ∫ du / sqrt(u^2 + d^2) from u1 to u2, infinite if the point lies on the segment
+fn segment_log(u1: f64, u2: f64, d: f64) -> f64 {
+    if d > 0. {
+        (u2 / d).asinh() - (u1 / d).asinh()
+    } else if u1 > 0. {
+        (u2 / u1).ln()
+    } else if u2 < 0. {
+        (u1 / u2).ln()
+    } else {
+        f64::INFINITY
+    }
+}
+
+// distance from the point p to the segment a-b
+fn distance_to_segment(p: XY<f64>, a: XY<f64>, b: XY<f64>) -> f64 {
+    let ab = b - a;
+    let length_sq = ab.x.powi(2) + ab.y.powi(2);
+    let t = if length_sq > 0. {
+        (((p.x - a.x) * ab.x + (p.y - a.y) * ab.y) / length_sq).clamp(0., 1.)
+    } else {
+        0.
+    };
+    (p - (a + ab * t)).length()
+}
+
+// field [V/m] and potential [V] of a uniformly charged segment a-b, all
+// positions in metres
+fn segment_field(p: XY<f64>, a: XY<f64>, b: XY<f64>, lambda: f64) -> (XY<f64>, f64) {
+    let ab = b - a;
+    let length = ab.length();
+    if length == 0. {
+        return (XY::zero(), 0.);
+    }
+    let t = ab * (1. / length);
+    // coordinates of the ends along the segment, relative to the foot of the
+    // perpendicular from p, and the distance from the line
+    let u1 = (a.x - p.x) * t.x + (a.y - p.y) * t.y;
+    let u2 = u1 + length;
+    let foot = a - t * u1;
+    let mut normal = p - foot;
+    let mut d = normal.length();
+    if d < length * 1e-12 {
+        // on the line, outside of the segment the field is parallel to it
+        d = 0.;
+        normal = XY::zero();
+    } else {
+        normal = normal * (1. / d);
+    }
+    let (r1, r2) = (
+        (u1.powi(2) + d.powi(2)).sqrt(),
+        (u2.powi(2) + d.powi(2)).sqrt(),
+    );
+
+    let parallel = K * lambda * (1. / r2 - 1. / r1);
+    let perpendicular = if d > 0. {
+        K * lambda / d * (u2 / r2 - u1 / r1)
+    } else {
+        0.
+    };
+    (
+        t * parallel + normal * perpendicular,
+        K * lambda * segment_log(u1, u2, d),
+    )
+}
+
+// field [V/m] and potential [V] of a uniformly charged rectangle in the plane,
+// all positions in metres
+fn rectangle_field(p: XY<f64>, min: XY<f64>, max: XY<f64>, sigma: f64) -> (XY<f64>, f64) {
+    // corners relative to the point
+    let (x1, x2) = (min.x - p.x, max.x - p.x);
+    let (y1, y2) = (min.y - p.y, max.y - p.y);
+    let mut intensity = XY::zero();
+    let mut potential = 0.;
+    // V = kσ Σ ±F(x, y) over the corners, F = x ln(y + r) + y ln(x + r),
+    // grouped by the edges, so the singular terms are never evaluated
+    for (sign, x) in [(-1., x1), (1., x2)] {
+        let log = segment_log(y1, y2, x.abs());
+        intensity.x += sign * log;
+        if x != 0. {
+            potential += sign * x * log;
+        }
+    }
+    for (sign, y) in [(-1., y1), (1., y2)] {
+        let log = segment_log(x1, x2, y.abs());
+        intensity.y += sign * log;
+        if y != 0. {
+            potential += sign * y * log;
+        }
+    }
+    (intensity * (K * sigma), K * sigma * potential)
+}
+
+impl ChargeDistribution {
+    // linear or surface density, only the sign is needed for drawing
+    pub fn density(&self) -> f64 {
+        match *self {
+            ChargeDistribution::Line { lambda, .. } | ChargeDistribution::Arc { lambda, .. } => {
+                lambda
+            }
+            ChargeDistribution::Rectangle { sigma, .. } => sigma,
+        }
+    }
+
+    // the chords approximating the shape (a single one for a line segment),
+    // with their linear densities, positions in cells
+    pub fn segments(&self) -> Vec<(XY<f64>, XY<f64>, f64)> {
+        match *self {
+            ChargeDistribution::Line {
+                x1,
+                y1,
+                x2,
+                y2,
+                lambda,
+            } => vec![(XY { x: x1, y: y1 }, XY { x: x2, y: y2 }, lambda)],
+            ChargeDistribution::Arc {
+                x,
+                y,
+                r,
+                start_angle,
+                end_angle,
+                lambda,
+            } => {
+                let (start, end) = (start_angle.to_radians(), end_angle.to_radians());
+                let count = ((end - start).abs() / (2. * PI) * RING_SEGMENTS)
+                    .ceil()
+                    .max(4.) as usize;
+                let step = (end - start) / count as f64;
+                // the chords are shorter than the arc, so the density is scaled
+                // to keep the total charge
+                let lambda = if step != 0. {
+                    lambda * (step / 2.).abs() / (step / 2.).sin().abs()
+                } else {
+                    lambda
+                };
+                let point = |angle: f64| XY {
+                    x: x + r * angle.cos(),
+                    y: y + r * angle.sin(),
+                };
+                (0..count)
+                    .map(|i| {
+                        let angle = start + step * i as f64;
+                        (point(angle), point(angle + step), lambda)
+                    })
+                    .collect()
+            }
+            ChargeDistribution::Rectangle { .. } => Vec::new(),
+        }
+    }
+
+    // distance from (x, y) to the source (in cells), zero inside of the rectangle
+    pub fn distance_to(&self, x: f64, y: f64) -> f64 {
+        match *self {
+            ChargeDistribution::Rectangle {
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+                ..
+            } => {
+                let dx = (x_min.min(x_max) - x).max(x - x_min.max(x_max)).max(0.);
+                let dy = (y_min.min(y_max) - y).max(y - y_min.max(y_max)).max(0.);
+                (dx.powi(2) + dy.powi(2)).sqrt()
+            }
+            _ => self
+                .segments()
+                .into_iter()
+                .map(|(a, b, _)| distance_to_segment(XY { x, y }, a, b))
+                .fold(f64::INFINITY, f64::min),
+        }
+    }
+
+    // Field intensity [V/m] and potential [V] at (x, y) (in cells), or None if the
+    // point is closer than `min_distance` (in cells) to the source (or inside
+    // the rectangle, when min_distance > 0), same as for the point charges.
+    pub fn field_at(
+        &self,
+        x: f64,
+        y: f64,
+        cell_size: f64,
+        min_distance: f64,
+    ) -> Option<(XY<f64>, f64)> {
+        if min_distance > 0. && self.distance_to(x, y) < min_distance {
+            return None;
+        }
+        let point = XY { x, y };
+        let (intensity, potential) = match *self {
+            ChargeDistribution::Rectangle {
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+                sigma,
+            } => {
+                let (min, max) = (
+                    XY {
+                        x: x_min.min(x_max),
+                        y: y_min.min(y_max),
+                    },
+                    XY {
+                        x: x_min.max(x_max),
+                        y: y_min.max(y_max),
+                    },
+                );
+                rectangle_field(point * cell_size, min * cell_size, max * cell_size, sigma)
+            }
+            _ => {
+                let mut intensity = XY::zero();
+                let mut potential = 0.;
+                for (a, b, lambda) in self.segments() {
+                    let (segment_intensity, segment_potential) =
+                        segment_field(point * cell_size, a * cell_size, b * cell_size, lambda);
+                    intensity += segment_intensity;
+                    potential += segment_potential;
+                }
+                (intensity, potential)
+            }
+        };
+        // exactly on the source
+        if !potential.is_finite() || !intensity.x.is_finite() || !intensity.y.is_finite() {
+            return None;
+        }
+        Some((intensity, potential))
+    }
+}
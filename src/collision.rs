@@ -0,0 +1,49 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+// a bouncing charge may be reflected this many times during a single step
+// (between a few stationary charges), then it waits for the next step
+pub const MAX_BOUNCES: usize = 8;
+
+// What happens when a movable charge comes closer than the collision radius to
+// a stationary point charge. Collisions with the distributions and conductors
+// always stop the charge, so do the collisions of the movable charges with each
+// other (in N-body mode), unless the field is softened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum CollisionModel {
+    // the charge stops where it is
+    #[value(name = "zatrzymanie")]
+    #[serde(rename = "zatrzymanie")]
+    Stop,
+    // the charge is absorbed, its charge is added to the stationary charge
+    #[value(name = "pochloniecie")]
+    #[serde(rename = "pochloniecie")]
+    Absorb,
+    // elastic bounce off a sphere with the collision radius
+    #[value(name = "odbicie")]
+    #[serde(rename = "odbicie")]
+    Bounce,
+    // no collisions, the field of the point charges is softened (Plummer
+    // model) with the collision radius as the softening length
+    #[value(name = "zmiekczenie")]
+    #[serde(rename = "zmiekczenie")]
+    Soften,
+}
+
+impl CollisionModel {
+    pub const ALL: [CollisionModel; 4] = [
+        CollisionModel::Stop,
+        CollisionModel::Absorb,
+        CollisionModel::Bounce,
+        CollisionModel::Soften,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CollisionModel::Stop => "zatrzymanie",
+            CollisionModel::Absorb => "pochłonięcie z przekazaniem ładunku",
+            CollisionModel::Bounce => "odbicie sprężyste",
+            CollisionModel::Soften => "zmiękczenie (Plummer)",
+        }
+    }
+}
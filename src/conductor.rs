@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+use crate::shape::Shape;
+
+// Conductors (electrodes, grounded plates) held at a fixed potential [V].
+// Every cell of the grid whose center lies inside the shape is kept at the
+// potential by the grid solver. In the scenario file every conductor is a
+// [[przewodniki]] table with the keys of the shape and `potencjal`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Conductor {
+    #[serde(flatten)]
+    pub shape: Shape,
+    #[serde(rename = "potencjal")]
+    pub potential: f64,
+}
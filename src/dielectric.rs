@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+
+use crate::shape::Shape;
+
+// Regions filled with a linear dielectric of relative permittivity ε_r. Every
+// cell of the grid whose center lies inside the shape takes the permittivity,
+// the rest of the plane is vacuum. In the scenario file every region is a
+// [[dielektryki]] table with the keys of the shape and `przenikalnosc`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Dielectric {
+    #[serde(flatten)]
+    pub shape: Shape,
+    #[serde(rename = "przenikalnosc")]
+    pub permittivity: f64,
+}
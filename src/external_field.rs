@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+use crate::lib::helpers::XY;
+
+// External electric field superposed on the field of the stationary charges.
+// Positions (x0, y0, region bounds) are given in cells like the charges, the
+// intensity in V/m and the gradients in V/m². In the scenario file every field
+// is a [[pole_zewnetrzne]] table with `typ` selecting the variant.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "typ", deny_unknown_fields)]
+pub enum ExternalField {
+    // the same intensity everywhere
+    #[serde(rename = "jednorodne")]
+    Uniform { ex: f64, ey: f64 },
+    // E(r) = E0 + G (r - r0), where G is symmetric (dEx/dy = dEy/dx), so that
+    // the field has a potential
+    #[serde(rename = "liniowe")]
+    LinearGradient {
+        ex: f64,
+        ey: f64,
+        #[serde(default)]
+        x0: f64,
+        #[serde(default)]
+        y0: f64,
+        #[serde(default)]
+        dex_dx: f64,
+        #[serde(default)]
+        dey_dy: f64,
+        #[serde(default)]
+        dex_dy: f64,
+    },
+    // uniform field inside the rectangle, zero outside (e.g. a capacitor gap)
+    #[serde(rename = "obszar")]
+    Region {
+        ex: f64,
+        ey: f64,
+        x_min: f64,
+        x_max: f64,
+        y_min: f64,
+        y_max: f64,
+    },
+}
+
+impl ExternalField {
+    // intensity [V/m] and potential [V] at (x, y) (in cells). The potential is
+    // zero at the origin of the grid, for regions it's taken at the nearest
+    // point of the region, so it stays continuous (fringe fields are ignored).
+    pub fn intensity_potential(&self, x: f64, y: f64, cell_size: f64) -> (XY<f64>, f64) {
+        match *self {
+            ExternalField::Uniform { ex, ey } => {
+                (XY { x: ex, y: ey }, -(ex * x + ey * y) * cell_size)
+            }
+            ExternalField::LinearGradient {
+                ex,
+                ey,
+                x0,
+                y0,
+                dex_dx,
+                dey_dy,
+                dex_dy,
+            } => {
+                let (dx, dy) = ((x - x0) * cell_size, (y - y0) * cell_size);
+                let intensity = XY {
+                    x: ex + dex_dx * dx + dex_dy * dy,
+                    y: ey + dex_dy * dx + dey_dy * dy,
+                };
+                // minus the integral of E from the origin of the grid, E(r0) = E0
+                let potential_at = |dx: f64, dy: f64| {
+                    -(ex * dx
+                        + ey * dy
+                        + 0.5 * (dex_dx * dx.powi(2) + dey_dy * dy.powi(2))
+                        + dex_dy * dx * dy)
+                };
+                let potential =
+                    potential_at(dx, dy) - potential_at(-x0 * cell_size, -y0 * cell_size);
+                (intensity, potential)
+            }
+            ExternalField::Region {
+                ex,
+                ey,
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+            } => {
+                let inside = x >= x_min && x <= x_max && y >= y_min && y <= y_max;
+                let intensity = if inside {
+                    XY { x: ex, y: ey }
+                } else {
+                    XY::zero()
+                };
+                // not clamp, so a region with swapped bounds doesn't panic
+                let (x, y) = (x.max(x_min).min(x_max), y.max(y_min).min(y_max));
+                (intensity, -(ex * x + ey * y) * cell_size)
+            }
+        }
+    }
+}
+
+// sum of all external fields at (x, y) (in cells)
+pub fn external_intensity_potential(
+    fields: &[ExternalField],
+    x: f64,
+    y: f64,
+    cell_size: f64,
+) -> (XY<f64>, f64) {
+    fields
+        .iter()
+        .fold((XY::zero(), 0.), |(intensity, potential), field| {
+            let (field_intensity, field_potential) = field.intensity_potential(x, y, cell_size);
+            (intensity + field_intensity, potential + field_potential)
+        })
+}
@@ -0,0 +1,611 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::lib::helpers::XY;
+
+// State of a group of charges that are integrated together. A group is either
+// a single charge, or all of the moving charges when they interact with each other.
+#[derive(Clone)]
+pub struct State {
+    pub x: Vec<XY<f64>>,
+    // velocity, or the momentum per unit mass (γv) in the relativistic mode
+    pub v: Vec<XY<f64>>,
+    // acceleration (dv/dt) at the current positions
+    pub a: Vec<XY<f64>>,
+}
+
+// Returns the accelerations of all charges in the group at the given positions,
+// or the indices (within the group) of the charges that collided.
+pub type Acceleration<'a> = dyn Fn(&[XY<f64>]) -> Result<Vec<XY<f64>>, Vec<usize>> + 'a;
+
+// Returns the cyclotron frequency ω = q Bz / m [rad/s] of every charge in the
+// group at the given positions.
+pub type Gyration<'a> = dyn Fn(&[XY<f64>]) -> Vec<f64> + 'a;
+
+// Forces acting on a group of charges: the electric acceleration depends only on
+// the positions, the magnetic one (v × B) also on the velocities.
+// In the relativistic mode the integrators evolve the momentum per unit mass
+// u = γv instead of the velocity, du/dt = q/m (E + v × B) and dx/dt = u / γ,
+// so the speed always stays below the speed of light.
+pub struct Forces<'a> {
+    pub electric: &'a Acceleration<'a>,
+    // None when there is no magnetic field
+    pub gyration: Option<&'a Gyration<'a>>,
+    // speed of light in grid units (cells/s), None in the classical mode
+    pub light_speed: Option<f64>,
+}
+
+impl Forces<'_> {
+    // full Lorentz acceleration q/m (E + v × B), with B = (0, 0, Bz)
+    pub fn acceleration(&self, x: &[XY<f64>], v: &[XY<f64>]) -> Result<Vec<XY<f64>>, Vec<usize>> {
+        let mut a = (self.electric)(x)?;
+        if let Some(gyration) = self.gyration {
+            for ((a, v), omega) in a.iter_mut().zip(self.velocity(v)).zip(gyration(x)) {
+                *a += XY {
+                    x: v.y * omega,
+                    y: -v.x * omega,
+                };
+            }
+        }
+        Ok(a)
+    }
+
+    // Lorentz factor for the momentum per unit mass u (1 in the classical mode)
+    pub fn gamma(&self, u: XY<f64>) -> f64 {
+        match self.light_speed {
+            Some(c) => (1. + (u.x.powi(2) + u.y.powi(2)) / c.powi(2)).sqrt(),
+            None => 1.,
+        }
+    }
+
+    // dx/dt of the charges, the state velocities are returned unchanged in the
+    // classical mode
+    pub fn velocity(&self, v: &[XY<f64>]) -> Vec<XY<f64>> {
+        match self.light_speed {
+            Some(_) => v.iter().map(|&u| u * (1. / self.gamma(u))).collect(),
+            None => v.to_vec(),
+        }
+    }
+}
+
+// Integrator advances a group of charges by one time step.
+// If any of the acceleration evaluations reports a collision, the state is left
+// untouched and the collided charges are returned, so the caller can handle them.
+// Integrators are shared between threads when the charges are integrated in parallel
+pub trait Integrator: Sync {
+    fn step(&self, state: &mut State, delta_t: f64, forces: &Forces) -> Result<(), Vec<usize>>;
+
+    // Advances the state by the whole interval `delta_t`, calling `record` after
+    // every accepted step with the length of that step. Fixed step integrators
+    // take a single step, adaptive ones may split the interval into many,
+    // starting from (and updating) `suggested_dt`.
+    fn advance(
+        &self,
+        state: &mut State,
+        delta_t: f64,
+        _suggested_dt: &mut f64,
+        forces: &Forces,
+        record: &mut dyn FnMut(&State, f64),
+    ) -> Result<(), Vec<usize>> {
+        self.step(state, delta_t, forces)?;
+        record(state, delta_t);
+        Ok(())
+    }
+}
+
+// returns a + b * scale, element-wise
+fn add_scaled(a: &[XY<f64>], b: &[XY<f64>], scale: f64) -> Vec<XY<f64>> {
+    a.iter().zip(b).map(|(&a, &b)| a + b * scale).collect()
+}
+
+// Velocity Verlet (kick-drift-kick), uses the acceleration cached in the state
+// as a(x_n), so only one new field evaluation is needed per step. With a magnetic
+// field the new acceleration is evaluated at the predicted velocity v + a dt.
+pub struct VelocityVerlet;
+
+impl Integrator for VelocityVerlet {
+    fn step(&self, state: &mut State, delta_t: f64, forces: &Forces) -> Result<(), Vec<usize>> {
+        // x + v dt + a dt^2 / 2, written with the velocity at the middle of the
+        // step, so it also works for the relativistic mode
+        let half_v = forces.velocity(&add_scaled(&state.v, &state.a, 0.5 * delta_t));
+        let new_x = add_scaled(&state.x, &half_v, delta_t);
+        let predicted_v = add_scaled(&state.v, &state.a, delta_t);
+        let new_a = forces.acceleration(&new_x, &predicted_v)?;
+
+        for (i, new_a) in new_a.iter().enumerate() {
+            state.v[i] += (state.a[i] + *new_a) * (0.5 * delta_t);
+        }
+        state.x = new_x;
+        state.a = new_a;
+        Ok(())
+    }
+}
+
+// classic 4th order Runge-Kutta for the state (x, v), where dx/dt = v and dv/dt = a(x)
+pub struct RungeKutta4;
+
+impl Integrator for RungeKutta4 {
+    fn step(&self, state: &mut State, delta_t: f64, forces: &Forces) -> Result<(), Vec<usize>> {
+        let half_t = 0.5 * delta_t;
+
+        let v1 = state.v.clone();
+        let k1_x = forces.velocity(&v1);
+        let k1_v = forces.acceleration(&state.x, &v1)?;
+
+        let v2 = add_scaled(&state.v, &k1_v, half_t);
+        let k2_x = forces.velocity(&v2);
+        let k2_v = forces.acceleration(&add_scaled(&state.x, &k1_x, half_t), &v2)?;
+
+        let v3 = add_scaled(&state.v, &k2_v, half_t);
+        let k3_x = forces.velocity(&v3);
+        let k3_v = forces.acceleration(&add_scaled(&state.x, &k2_x, half_t), &v3)?;
+
+        let v4 = add_scaled(&state.v, &k3_v, delta_t);
+        let k4_x = forces.velocity(&v4);
+        let k4_v = forces.acceleration(&add_scaled(&state.x, &k3_x, delta_t), &v4)?;
+
+        let new_x: Vec<XY<f64>> = (0..state.x.len())
+            .map(|i| {
+                state.x[i] + (k1_x[i] + k2_x[i] * 2. + k3_x[i] * 2. + k4_x[i]) * (delta_t / 6.)
+            })
+            .collect();
+        for i in 0..state.v.len() {
+            state.v[i] += (k1_v[i] + k2_v[i] * 2. + k3_v[i] * 2. + k4_v[i]) * (delta_t / 6.);
+        }
+        // the acceleration at the new position is only used for display and history
+        state.a = forces.acceleration(&new_x, &state.v)?;
+        state.x = new_x;
+        Ok(())
+    }
+}
+
+// symplectic leapfrog in the drift-kick-drift form, one field evaluation per step
+// (at the midpoint of the step)
+pub struct Leapfrog;
+
+impl Integrator for Leapfrog {
+    fn step(&self, state: &mut State, delta_t: f64, forces: &Forces) -> Result<(), Vec<usize>> {
+        let half_x = add_scaled(&state.x, &forces.velocity(&state.v), 0.5 * delta_t);
+        // the magnetic force is explicit (uses the old velocity), see Boris for a
+        // version that conserves the speed during gyration
+        let a = forces.acceleration(&half_x, &state.v)?;
+
+        for (v, a) in state.v.iter_mut().zip(&a) {
+            *v += *a * delta_t;
+        }
+        state.x = add_scaled(&half_x, &forces.velocity(&state.v), 0.5 * delta_t);
+        state.a = a;
+        Ok(())
+    }
+}
+
+// Boris pusher in the drift-kick-drift form: the electric kick is split in two
+// halves, with the rotation of the velocity by the magnetic field between them.
+// The rotation doesn't change the speed, so gyration doesn't spiral out. Without
+// a magnetic field it's the same as Leapfrog.
+pub struct Boris;
+
+impl Integrator for Boris {
+    fn step(&self, state: &mut State, delta_t: f64, forces: &Forces) -> Result<(), Vec<usize>> {
+        let half_x = add_scaled(&state.x, &forces.velocity(&state.v), 0.5 * delta_t);
+        let electric = (forces.electric)(&half_x)?;
+        let gyration = match forces.gyration {
+            Some(gyration) => gyration(&half_x),
+            None => vec![0.; half_x.len()],
+        };
+
+        for ((v, a), omega) in state.v.iter_mut().zip(&electric).zip(&gyration) {
+            let v_minus = *v + *a * (0.5 * delta_t);
+            // rotation by the angle -omega * delta_t / γ (Bz along the z axis)
+            let t = 0.5 * omega * delta_t / forces.gamma(v_minus);
+            let s = 2. * t / (1. + t.powi(2));
+            let v_prime = XY {
+                x: v_minus.x + v_minus.y * t,
+                y: v_minus.y - v_minus.x * t,
+            };
+            let v_plus = XY {
+                x: v_minus.x + v_prime.y * s,
+                y: v_minus.y - v_prime.x * s,
+            };
+            *v = v_plus + *a * (0.5 * delta_t);
+        }
+        let velocity = forces.velocity(&state.v);
+        state.x = add_scaled(&half_x, &velocity, 0.5 * delta_t);
+        // full Lorentz acceleration at the midpoint, for display and history
+        state.a = (0..electric.len())
+            .map(|i| {
+                electric[i]
+                    + XY {
+                        x: velocity[i].y * gyration[i],
+                        y: -velocity[i].x * gyration[i],
+                    }
+            })
+            .collect();
+        Ok(())
+    }
+}
+
+// Dormand-Prince 5(4) coefficients (the nodes c_i are not needed, as the
+// acceleration doesn't depend on time)
+const DP_A: [[f64; 6]; 6] = [
+    [1. / 5., 0., 0., 0., 0., 0.],
+    [3. / 40., 9. / 40., 0., 0., 0., 0.],
+    [44. / 45., -56. / 15., 32. / 9., 0., 0., 0.],
+    [
+        19372. / 6561.,
+        -25360. / 2187.,
+        64448. / 6561.,
+        -212. / 729.,
+        0.,
+        0.,
+    ],
+    [
+        9017. / 3168.,
+        -355. / 33.,
+        46732. / 5247.,
+        49. / 176.,
+        -5103. / 18656.,
+        0.,
+    ],
+    [
+        35. / 384.,
+        0.,
+        500. / 1113.,
+        125. / 192.,
+        -2187. / 6784.,
+        11. / 84.,
+    ],
+];
+// difference between the 5th and the embedded 4th order weights
+const DP_E: [f64; 7] = [
+    71. / 57600.,
+    0.,
+    -71. / 16695.,
+    71. / 1920.,
+    -17253. / 339200.,
+    22. / 525.,
+    -1. / 40.,
+];
+// limits for shrinking and growing the step after a single attempt
+const DP_MIN_FACTOR: f64 = 0.2;
+const DP_MAX_FACTOR: f64 = 5.;
+const DP_SAFETY: f64 = 0.9;
+// steps shorter than this fraction of the interval are accepted regardless of
+// the error, so a single interval takes at most 10^4 accepted steps and the
+// simulation can't get stuck crawling next to a charge
+const DP_MIN_STEP_FRACTION: f64 = 1e-4;
+
+// Adaptive embedded Runge-Kutta (Dormand-Prince 5(4)). The step is shrunk when
+// the estimated local error exceeds `tolerance` (relative to the size of the
+// position and velocity), and grown again when the field is smooth.
+pub struct DormandPrince {
+    pub tolerance: f64,
+}
+
+impl DormandPrince {
+    // returns the new state and the normalized error estimate (accept if <= 1)
+    fn try_step(&self, state: &State, h: f64, forces: &Forces) -> Result<(State, f64), Vec<usize>> {
+        let mut k_x = vec![forces.velocity(&state.v)];
+        let mut k_v = vec![forces.acceleration(&state.x, &state.v)?];
+        let mut stage_x = state.x.clone();
+        let mut stage_v = state.v.clone();
+
+        for coefficients in DP_A {
+            stage_x = state.x.clone();
+            stage_v = state.v.clone();
+            for (j, &coefficient) in coefficients.iter().enumerate().take(k_x.len()) {
+                for i in 0..stage_x.len() {
+                    stage_x[i] += k_x[j][i] * (h * coefficient);
+                    stage_v[i] += k_v[j][i] * (h * coefficient);
+                }
+            }
+            k_v.push(forces.acceleration(&stage_x, &stage_v)?);
+            k_x.push(forces.velocity(&stage_v));
+        }
+
+        // the last stage is evaluated at the 5th order solution (FSAL)
+        let new_state = State {
+            x: stage_x,
+            v: stage_v,
+            a: k_v[6].clone(),
+        };
+
+        let mut error_sq = 0.;
+        let mut components = 0;
+        for i in 0..state.x.len() {
+            let mut error_x = XY::zero();
+            let mut error_v = XY::zero();
+            for (j, e) in DP_E.iter().enumerate() {
+                error_x += k_x[j][i] * (h * e);
+                error_v += k_v[j][i] * (h * e);
+            }
+            for (error, old, new) in [
+                (error_x.x, state.x[i].x, new_state.x[i].x),
+                (error_x.y, state.x[i].y, new_state.x[i].y),
+                (error_v.x, state.v[i].x, new_state.v[i].x),
+                (error_v.y, state.v[i].y, new_state.v[i].y),
+            ] {
+                let scale = self.tolerance * (1. + old.abs().max(new.abs()));
+                error_sq += (error / scale).powi(2);
+                components += 1;
+            }
+        }
+
+        Ok((new_state, (error_sq / components as f64).sqrt()))
+    }
+}
+
+impl Integrator for DormandPrince {
+    fn step(&self, state: &mut State, delta_t: f64, forces: &Forces) -> Result<(), Vec<usize>> {
+        let (new_state, _) = self.try_step(state, delta_t, forces)?;
+        *state = new_state;
+        Ok(())
+    }
+
+    fn advance(
+        &self,
+        state: &mut State,
+        delta_t: f64,
+        suggested_dt: &mut f64,
+        forces: &Forces,
+        record: &mut dyn FnMut(&State, f64),
+    ) -> Result<(), Vec<usize>> {
+        let min_step = delta_t * DP_MIN_STEP_FRACTION;
+        let mut t = 0.;
+        // start from the step suggested at the end of the previous interval
+        let mut h = if suggested_dt.is_finite() && *suggested_dt > 0. {
+            suggested_dt.max(min_step)
+        } else {
+            delta_t
+        };
+
+        while t < delta_t {
+            let last_step = h >= delta_t - t;
+            let step = if last_step { delta_t - t } else { h };
+
+            let (new_state, error) = self.try_step(state, step, forces)?;
+
+            // a non-finite error estimate can't be trusted, shrink as much as
+            // allowed (a NaN factor would make every following step NaN)
+            let factor = if !error.is_finite() {
+                DP_MIN_FACTOR
+            } else if error == 0. {
+                DP_MAX_FACTOR
+            } else {
+                (DP_SAFETY * error.powf(-0.2)).clamp(DP_MIN_FACTOR, DP_MAX_FACTOR)
+            };
+
+            if error <= 1. || step <= min_step {
+                *state = new_state;
+                t = if last_step { delta_t } else { t + step };
+                record(state, step);
+                // don't let a shortened last step limit the next interval
+                if !last_step || factor < 1. {
+                    h = (step * factor).max(min_step);
+                }
+            } else {
+                h = (step * factor).max(min_step);
+            }
+        }
+
+        *suggested_dt = h;
+        Ok(())
+    }
+}
+
+// used to select the integrator from the command line and the GUI
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IntegratorKind {
+    Verlet,
+    Rk4,
+    Leapfrog,
+    Rk45,
+    Boris,
+}
+
+impl IntegratorKind {
+    pub const ALL: [IntegratorKind; 5] = [
+        IntegratorKind::Verlet,
+        IntegratorKind::Rk4,
+        IntegratorKind::Leapfrog,
+        IntegratorKind::Rk45,
+        IntegratorKind::Boris,
+    ];
+
+    // `tolerance` is only used by the adaptive integrator
+    pub fn integrator(&self, tolerance: f64) -> Box<dyn Integrator> {
+        match self {
+            IntegratorKind::Verlet => Box::new(VelocityVerlet),
+            IntegratorKind::Rk4 => Box::new(RungeKutta4),
+            IntegratorKind::Leapfrog => Box::new(Leapfrog),
+            IntegratorKind::Rk45 => Box::new(DormandPrince { tolerance }),
+            IntegratorKind::Boris => Box::new(Boris),
+        }
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        matches!(self, IntegratorKind::Rk45)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IntegratorKind::Verlet => "Velocity Verlet",
+            IntegratorKind::Rk4 => "Runge-Kutta 4",
+            IntegratorKind::Leapfrog => "Leapfrog",
+            IntegratorKind::Rk45 => "Dormand-Prince (adaptacyjny)",
+            IntegratorKind::Boris => "Boris (pole magnetyczne)",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn electric_only<'a>(electric: &'a Acceleration<'a>) -> Forces<'a> {
+        Forces {
+            electric,
+            gyration: None,
+            light_speed: None,
+        }
+    }
+
+    // two equal charges of opposite sign (unit mass, unit coupling constant)
+    // attracting each other
+    fn coulomb_pair(positions: &[XY<f64>]) -> Result<Vec<XY<f64>>, Vec<usize>> {
+        let d = positions[0] + positions[1] * -1.;
+        let r = d.length();
+        let a = d * (-1. / r.powi(3));
+        Ok(vec![a, a * -1.])
+    }
+
+    fn pair_energy(state: &State) -> f64 {
+        let kinetic: f64 = state
+            .v
+            .iter()
+            .map(|v| 0.5 * (v.x.powi(2) + v.y.powi(2)))
+            .sum();
+        kinetic - 1. / (state.x[0] + state.x[1] * -1.).length()
+    }
+
+    #[test]
+    fn two_body_orbit_energy_drift_is_bounded() {
+        for kind in IntegratorKind::ALL {
+            // slightly eccentric orbit, the period is ~3 time units
+            let x = vec![XY { x: -0.5, y: 0. }, XY { x: 0.5, y: 0. }];
+            let v = vec![XY { x: 0., y: -0.6 }, XY { x: 0., y: 0.6 }];
+            let a = coulomb_pair(&x).unwrap();
+            let mut state = State { x, v, a };
+            let initial_energy = pair_energy(&state);
+
+            let mut max_drift: f64 = 0.;
+            for _ in 0..20_000 {
+                kind.integrator(1e-9)
+                    .step(&mut state, 1e-3, &electric_only(&coulomb_pair))
+                    .unwrap();
+                let drift = (pair_energy(&state) - initial_energy) / initial_energy.abs();
+                max_drift = max_drift.max(drift.abs());
+            }
+            assert!(
+                max_drift < 1e-4,
+                "{}: relative energy drift {:e}",
+                kind.name(),
+                max_drift
+            );
+        }
+    }
+
+    // a charge attracted by a fixed charge at the origin
+    fn central_attraction(positions: &[XY<f64>]) -> Result<Vec<XY<f64>>, Vec<usize>> {
+        Ok(positions
+            .iter()
+            .map(|&x| x * (-1. / x.length().powi(3)))
+            .collect())
+    }
+
+    #[test]
+    fn adaptive_step_shrinks_near_charge_and_meets_tolerance() {
+        // eccentric orbit, the distance to the origin drops from 1 to ~0.05
+        let x = vec![XY { x: 1., y: 0. }];
+        let v = vec![XY { x: 0., y: 0.3 }];
+        let a = central_attraction(&x).unwrap();
+        let start = State { x, v, a };
+        let delta_t = 1.8;
+
+        let mut adaptive = start.clone();
+        let mut steps = Vec::new();
+        DormandPrince { tolerance: 1e-9 }
+            .advance(
+                &mut adaptive,
+                delta_t,
+                &mut 0.,
+                &electric_only(&central_attraction),
+                &mut |state, dt| steps.push((state.x[0].length(), dt)),
+            )
+            .unwrap();
+
+        let total: f64 = steps.iter().map(|(_, dt)| dt).sum();
+        assert!((total - delta_t).abs() < 1e-12);
+        // the steps close to the charge are much shorter than the ones far away
+        let closest = steps.iter().min_by(|a, b| a.0.total_cmp(&b.0)).unwrap();
+        let farthest = steps.iter().max_by(|a, b| a.0.total_cmp(&b.0)).unwrap();
+        assert!(
+            closest.1 * 10. < farthest.1,
+            "dt {:e} at r = {}, dt {:e} at r = {}",
+            closest.1,
+            closest.0,
+            farthest.1,
+            farthest.0
+        );
+
+        let mut reference = start;
+        let h = 1e-5;
+        for _ in 0..(delta_t / h).round() as usize {
+            RungeKutta4
+                .step(&mut reference, h, &electric_only(&central_attraction))
+                .unwrap();
+        }
+        let error = (adaptive.x[0] + reference.x[0] * -1.).length();
+        assert!(error < 1e-7, "position error {:e}", error);
+    }
+
+    #[test]
+    fn adaptive_step_terminates_on_a_singular_field() {
+        // an infinite acceleration makes every error estimate non-finite
+        let singular = |positions: &[XY<f64>]| -> Result<Vec<XY<f64>>, Vec<usize>> {
+            Ok(vec![
+                XY {
+                    x: f64::INFINITY,
+                    y: 0.
+                };
+                positions.len()
+            ])
+        };
+        let mut state = State {
+            x: vec![XY::zero()],
+            v: vec![XY::zero()],
+            a: vec![XY::zero()],
+        };
+        let mut steps = 0;
+        DormandPrince { tolerance: 1e-6 }
+            .advance(
+                &mut state,
+                1.,
+                &mut 0.,
+                &electric_only(&singular),
+                &mut |_, _| steps += 1,
+            )
+            .unwrap();
+        assert!(steps <= 10_001, "{} steps", steps);
+    }
+
+    #[test]
+    fn boris_keeps_gyration_radius_and_speed() {
+        // uniform Bz without an electric field, ω = 1 rad/s, so the charge moves
+        // on a circle of radius |v| / ω = 2 around (0, 0)
+        let no_field = |positions: &[XY<f64>]| Ok(vec![XY::zero(); positions.len()]);
+        let gyration = |positions: &[XY<f64>]| vec![1.; positions.len()];
+        let forces = Forces {
+            electric: &no_field,
+            gyration: Some(&gyration),
+            light_speed: None,
+        };
+        let mut state = State {
+            x: vec![XY { x: -2., y: 0. }],
+            v: vec![XY { x: 0., y: 2. }],
+            a: vec![XY { x: 2., y: 0. }],
+        };
+
+        // 100 periods with ~600 steps each
+        let mut max_radius_error: f64 = 0.;
+        let mut max_speed_error: f64 = 0.;
+        for _ in 0..63_000 {
+            Boris.step(&mut state, 0.01, &forces).unwrap();
+            max_radius_error = max_radius_error.max((state.x[0].length() - 2.).abs());
+            max_speed_error = max_speed_error.max((state.v[0].length() - 2.).abs());
+        }
+        assert!(max_radius_error < 1e-9, "promień {:e}", max_radius_error);
+        assert!(max_speed_error < 1e-12, "prędkość {:e}", max_speed_error);
+    }
+}
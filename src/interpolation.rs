@@ -0,0 +1,93 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::lib::helpers::XY;
+
+// How the field acting on the movable charges is evaluated: summed directly
+// from all sources at every step, or interpolated from the field stored in
+// the cells of the grid (much faster with many sources, but only as accurate
+// as the grid resolution allows)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum FieldLookup {
+    #[value(name = "bezposrednio")]
+    #[serde(rename = "bezposrednio")]
+    Direct,
+    #[value(name = "dwuliniowa")]
+    #[serde(rename = "dwuliniowa")]
+    Bilinear,
+    #[value(name = "dwuszescienna")]
+    #[serde(rename = "dwuszescienna")]
+    Bicubic,
+}
+
+impl FieldLookup {
+    pub const ALL: [FieldLookup; 3] = [
+        FieldLookup::Direct,
+        FieldLookup::Bilinear,
+        FieldLookup::Bicubic,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldLookup::Direct => "bezpośrednio",
+            FieldLookup::Bilinear => "interpolacja dwuliniowa",
+            FieldLookup::Bicubic => "interpolacja dwusześcienna",
+        }
+    }
+}
+
+// Catmull-Rom weights of the samples at -1, 0, 1 and 2 for 0 <= t <= 1
+fn cubic_weights(t: f64) -> [f64; 4] {
+    let (t2, t3) = (t * t, t * t * t);
+    [
+        (-t3 + 2. * t2 - t) / 2.,
+        (3. * t3 - 5. * t2 + 2.) / 2.,
+        (-3. * t3 + 4. * t2 + t) / 2.,
+        (t3 - t2) / 2.,
+    ]
+}
+
+// Interpolates the intensity and potential sampled at the integer positions
+// of a w x h grid (`sample(x, y)`) at the point (x, y) (in cells). None
+// outside of the grid or for the direct lookup. The bicubic samples beyond the
+// edges are replaced by the nearest ones. Samples with weight zero are never
+// read, so a point exactly at a cell only depends on that cell.
+pub fn interpolate(
+    lookup: FieldLookup,
+    w: usize,
+    h: usize,
+    x: f64,
+    y: f64,
+    sample: impl Fn(usize, usize) -> (XY<f64>, f64),
+) -> Option<(XY<f64>, f64)> {
+    if !(x >= 0. && y >= 0. && x <= (w - 1) as f64 && y <= (h - 1) as f64) {
+        return None;
+    }
+    let (x0, y0) = (
+        (x.floor() as usize).min(w.saturating_sub(2)),
+        (y.floor() as usize).min(h.saturating_sub(2)),
+    );
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    // weights of the samples at x0 - 1 ..= x0 + 2
+    let (weights_x, weights_y) = match lookup {
+        FieldLookup::Direct => return None,
+        FieldLookup::Bilinear => ([0., 1. - fx, fx, 0.], [0., 1. - fy, fy, 0.]),
+        FieldLookup::Bicubic => (cubic_weights(fx), cubic_weights(fy)),
+    };
+    let mut intensity = XY::zero();
+    let mut potential = 0.;
+    for (j, weight_y) in weights_y.into_iter().enumerate() {
+        for (i, weight_x) in weights_x.into_iter().enumerate() {
+            let weight = weight_x * weight_y;
+            if weight == 0. {
+                continue;
+            }
+            let cx = (x0 + i).saturating_sub(1).min(w - 1);
+            let cy = (y0 + j).saturating_sub(1).min(h - 1);
+            let (sample_intensity, sample_potential) = sample(cx, cy);
+            intensity += sample_intensity * weight;
+            potential += sample_potential * weight;
+        }
+    }
+    Some((intensity, potential))
+}
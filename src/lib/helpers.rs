@@ -1,4 +1,10 @@
-use std::{fs, io::Write, path::Path, str::FromStr};
+use std::{
+    fs,
+    io::Write,
+    ops::{Add, AddAssign, Mul, Sub},
+    path::Path,
+    str::FromStr,
+};
 
 #[derive(Clone, Copy, Debug)]
 pub struct XY<T> {
@@ -20,6 +26,46 @@ impl XY<f64> {
     pub fn angle(&self) -> f64 {
         self.y.atan2(self.x)
     }
+    pub fn zero() -> Self {
+        XY { x: 0., y: 0. }
+    }
+}
+
+impl Add for XY<f64> {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        XY {
+            x: self.x + other.x,
+            y: self.y + other.y,
+        }
+    }
+}
+
+impl AddAssign for XY<f64> {
+    fn add_assign(&mut self, other: Self) {
+        self.x += other.x;
+        self.y += other.y;
+    }
+}
+
+impl Sub for XY<f64> {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        XY {
+            x: self.x - other.x,
+            y: self.y - other.y,
+        }
+    }
+}
+
+impl Mul<f64> for XY<f64> {
+    type Output = Self;
+    fn mul(self, scalar: f64) -> Self {
+        XY {
+            x: self.x * scalar,
+            y: self.y * scalar,
+        }
+    }
 }
 
 pub fn read_input<T: FromStr>(message: &str) -> T
@@ -86,3 +132,5 @@ pub fn ensure_files_exist() {
 }
 
 pub const K: f64 = 8.99e9;
+// speed of light [m/s]
+pub const SPEED_OF_LIGHT: f64 = 299_792_458.;
@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+// Static magnetic field perpendicular to the simulation plane, B = (0, 0, Bz).
+// Bz is given in T, positions in cells and the gradients in T/m. In the
+// scenario file every field is a [[pole_magnetyczne]] table with `typ`
+// selecting the variant, same as the external electric fields.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "typ", deny_unknown_fields)]
+pub enum MagneticField {
+    #[serde(rename = "jednorodne")]
+    Uniform { bz: f64 },
+    // Bz(r) = Bz0 + (dBz/dx, dBz/dy) · (r - r0)
+    #[serde(rename = "liniowe")]
+    LinearGradient {
+        bz: f64,
+        #[serde(default)]
+        x0: f64,
+        #[serde(default)]
+        y0: f64,
+        #[serde(default)]
+        dbz_dx: f64,
+        #[serde(default)]
+        dbz_dy: f64,
+    },
+    // uniform field inside the rectangle, zero outside (e.g. a mass spectrometer)
+    #[serde(rename = "obszar")]
+    Region {
+        bz: f64,
+        x_min: f64,
+        x_max: f64,
+        y_min: f64,
+        y_max: f64,
+    },
+}
+
+impl MagneticField {
+    // Bz [T] at (x, y) (in cells)
+    pub fn bz_at(&self, x: f64, y: f64, cell_size: f64) -> f64 {
+        match *self {
+            MagneticField::Uniform { bz } => bz,
+            MagneticField::LinearGradient {
+                bz,
+                x0,
+                y0,
+                dbz_dx,
+                dbz_dy,
+            } => bz + (dbz_dx * (x - x0) + dbz_dy * (y - y0)) * cell_size,
+            MagneticField::Region {
+                bz,
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+            } => {
+                if x >= x_min && x <= x_max && y >= y_min && y <= y_max {
+                    bz
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+}
+
+// sum of all magnetic fields at (x, y) (in cells)
+pub fn magnetic_field_at(fields: &[MagneticField], x: f64, y: f64, cell_size: f64) -> f64 {
+    fields
+        .iter()
+        .map(|field| field.bz_at(x, y, cell_size))
+        .sum()
+}
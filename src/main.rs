@@ -1,6 +1,9 @@
+#![allow(special_module_name)]
+
 use clap::Parser;
 use egui::Pos2;
 use macroquad::{self, prelude::*};
+use rayon::prelude::*;
 use std::{time::Instant};
 use colored::Colorize;
 
@@ -8,7 +11,53 @@ mod cellgrid;
 use cellgrid::*;
 
 mod movable_charge;
-use movable_charge::*;
+
+mod integrator;
+use integrator::IntegratorKind;
+
+mod interpolation;
+use interpolation::FieldLookup;
+
+mod barnes_hut;
+
+mod boundary;
+use boundary::{Boundaries, Boundary};
+
+mod charge_distribution;
+use charge_distribution::ChargeDistribution;
+
+mod collision;
+use collision::CollisionModel;
+
+mod conductor;
+
+mod dielectric;
+
+mod shape;
+use shape::Shape;
+
+mod poisson;
+
+mod external_field;
+
+mod magnetic_field;
+
+mod metadata;
+
+mod npy;
+
+mod output_format;
+use output_format::OutputFormat;
+
+mod trajectory;
+
+mod vtk;
+use vtk::FrameSeries;
+
+mod waveform;
+
+mod scenario;
+use scenario::{Scenario, ScenarioError};
 
 mod lib;
 use lib::toggle;
@@ -19,52 +68,119 @@ enum MouseCharge {
     Negative,
 }
 
+// what is shown as the background of the simulation
+#[derive(Clone, Copy, PartialEq)]
+enum Background {
+    Potential,
+    Intensity,
+    MagneticField,
+    Permittivity,
+}
+
+impl Background {
+    const ALL: [Background; 4] = [
+        Background::Potential,
+        Background::Intensity,
+        Background::MagneticField,
+        Background::Permittivity,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Background::Potential => "potencjał",
+            Background::Intensity => "natężenie pola",
+            Background::MagneticField => "pole magnetyczne",
+            Background::Permittivity => "przenikalność",
+        }
+    }
+}
+
 fn fill_texture_with_intensity(
-    potential_mode: bool,
-    stationary_charges: &Vec<StationaryCharge>,
+    background: Background,
+    cellgrid: &CellGrid,
     intensity_percentile: f64,
     potential_percentile: f64,
-    cellgrid_w: usize,
-    cellgrid_h: usize,
     screen_w: f32,
     screen_h: f32,
 ) -> Texture2D {
+    let (cellgrid_w, cellgrid_h) = cellgrid.get_dimensions();
     let mut image = Image::gen_image_color(screen_w as u16, screen_h as u16, BLACK);
-    // display intensity
-    for y in 0..screen_h as u32 {
-        for x in 0..screen_w as u32 {
-            let virtual_x = (x as f64 / screen_w as f64) * cellgrid_w as f64;
-            let virtual_y = (y as f64 / screen_h as f64) * cellgrid_h as f64;
-            let intensity_potential_option =
-                field_intensity_potential(virtual_x, virtual_y, stationary_charges);
-
-            let (intensity, potential) = match intensity_potential_option {
-                Some((intensity, potential)) => (intensity, potential),
-                None => (f64::INFINITY, f64::INFINITY),
-            };
+    let magnetic_max = match background {
+        Background::MagneticField => cellgrid.magnetic_field_max(),
+        _ => 0.,
+    };
+    let permittivity_max = match background {
+        Background::Permittivity => cellgrid.permittivity_max(),
+        _ => 1.,
+    };
+    let row_length = image.width() * 4;
+    // display intensity, every row of pixels is computed on a separate thread
+    image
+        .bytes
+        .par_chunks_mut(row_length)
+        .enumerate()
+        .for_each(|(y, row)| {
+            for (x, pixel) in row.chunks_mut(4).enumerate() {
+                // sample at the center of the pixel, cell centers are at integer positions
+                let virtual_x = ((x as f64 + 0.5) / screen_w as f64) * cellgrid_w as f64 - 0.5;
+                let virtual_y = ((y as f64 + 0.5) / screen_h as f64) * cellgrid_h as f64 - 0.5;
+                if background == Background::MagneticField {
+                    // green for Bz > 0 (out of the screen), magenta for Bz < 0
+                    let bz = cellgrid.magnetic_field_at(virtual_x, virtual_y);
+                    let saturation = if magnetic_max > 0. {
+                        (bz.abs() / magnetic_max) as f32
+                    } else {
+                        0.
+                    };
+                    let color = if bz > 0. {
+                        Color::new(0., saturation, 0., 1.0)
+                    } else {
+                        Color::new(saturation, 0., saturation, 1.0)
+                    };
+                    let color: [u8; 4] = color.into();
+                    pixel.copy_from_slice(&color);
+                    continue;
+                }
+                if background == Background::Permittivity {
+                    // vacuum is black, the densest dielectric yellow
+                    let permittivity = cellgrid.permittivity_at(virtual_x, virtual_y);
+                    let saturation = if permittivity_max > 1. {
+                        ((permittivity - 1.) / (permittivity_max - 1.)) as f32
+                    } else {
+                        0.
+                    };
+                    let color: [u8; 4] = Color::new(saturation, saturation, 0., 1.0).into();
+                    pixel.copy_from_slice(&color);
+                    continue;
+                }
+
+                let intensity_potential_option =
+                    cellgrid.intensity_potential_at(virtual_x, virtual_y);
+
+                let (intensity, potential) = match intensity_potential_option {
+                    Some((intensity, potential)) => (intensity.length(), potential),
+                    None => (f64::INFINITY, f64::INFINITY),
+                };
 
-            if potential_mode {
-                // if potential is greater than 0, then the charge is positive, so the color should be red
-                // if potential is less than 0, then the charge is negative, so the color should be blue
-                let saturation = (potential.abs() / potential_percentile) as f32;
-                let color = if potential > 0. {
-                    // red
-                    Color::new(saturation, 0., 0., 1.0)
+                let color = if background == Background::Potential {
+                    // if potential is greater than 0, then the charge is positive, so the color should be red
+                    // if potential is less than 0, then the charge is negative, so the color should be blue
+                    let saturation = (potential.abs() / potential_percentile) as f32;
+                    if potential > 0. {
+                        // red
+                        Color::new(saturation, 0., 0., 1.0)
+                    } else {
+                        // blue
+                        Color::new(0., 0., saturation, 1.0)
+                    }
                 } else {
-                    // blue
-                    Color::new(0., 0., saturation, 1.0)
+                    let intensity = (intensity / intensity_percentile) as f32;
+                    Color::new(intensity, intensity, intensity, 1.0)
                 };
-                image.set_pixel(x, y, color);
-            } else {
-                let intensity = 100. * (intensity.abs() / intensity_percentile) as f32;
-                image.set_pixel(
-                    x,
-                    y,
-                    Color::new(intensity, intensity, intensity, 1.0),
-                );
+                let color: [u8; 4] = color.into();
+                pixel.copy_from_slice(&color);
             }
-        }
-    }
+        });
 
     // let intensity = 1. * (cell.e.length() / field_intenity_percentile) as f32;
     // image.set_pixel(
@@ -76,6 +192,45 @@ fn fill_texture_with_intensity(
     Texture2D::from_image(&image)
 }
 
+// outline of a conductor or a dielectric, scaled like the charges
+fn draw_shape(shape: &Shape, scale_x: f32, scale_y: f32, thickness: f32, color: Color) {
+    match *shape {
+        Shape::Rectangle {
+            x_min,
+            x_max,
+            y_min,
+            y_max,
+        } => draw_rectangle_lines(
+            x_min.min(x_max) as f32 * scale_x + scale_x / 2.0,
+            y_min.min(y_max) as f32 * scale_y + scale_y / 2.0,
+            (x_max - x_min).abs() as f32 * scale_x,
+            (y_max - y_min).abs() as f32 * scale_y,
+            thickness,
+            color,
+        ),
+        Shape::Disk { x, y, r } => draw_circle_lines(
+            x as f32 * scale_x + scale_x / 2.0,
+            y as f32 * scale_y + scale_y / 2.0,
+            r as f32 * scale_x,
+            thickness,
+            color,
+        ),
+    }
+}
+
+// energy drift relative to the initial energy, or the absolute drift (in J) when
+// the initial energy is zero and the relative one is undefined
+fn format_energy_drift(initial_energy: f64, energy: f64) -> String {
+    let drift = energy - initial_energy;
+    if !drift.is_finite() {
+        "—".to_string()
+    } else if initial_energy == 0.0 {
+        format!("{:.3e}J", drift)
+    } else {
+        format!("{:.3e}", drift / initial_energy.abs())
+    }
+}
+
 // UI main loop
 async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
     let mut steps_by_frame = 1;
@@ -85,17 +240,24 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
 
     let mut draw_details = true;
     let mut draw_vectors = true;
+    let mut draw_stationary = true;
     let mut mouse_charge = MouseCharge::Positive;
-    let mut potential_display_mode = true;
+    let mut background = Background::Potential;
     let mut percentile = 0.95;
 
-    let mut old_potential_display_mode = potential_display_mode;
+    let mut old_background = background;
     let mut old_percentile = percentile;
+    let mut old_integrator = cellgrid.integrator;
+    let mut old_interactions = cellgrid.interactions;
+    let mut old_relativistic = cellgrid.relativistic;
+    let mut old_field_lookup = cellgrid.field_lookup;
+    let mut old_collision_model = cellgrid.collision_model;
+    let mut old_collision_radius = cellgrid.collision_radius;
+    let mut old_field_version = cellgrid.field_version;
+    let mut initial_energy = cellgrid.total_energy();
 
-    let (cellgrid_w, cellgrid_h) = cellgrid.get_dimensions();
     let mut screen_h = screen_height();
     let mut screen_w = screen_width();
-    let mut time_elapsed: f64 = 0.;
 
     let (mut intensity_percentile, mut potential_percentile) =
         cellgrid.field_percentiles(percentile);
@@ -105,12 +267,10 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
     let intensity_vector_scale: f32 = 4. * 10e-5;
 
     let mut texture = fill_texture_with_intensity(
-        potential_display_mode,
-        &cellgrid.stationary_charges,
+        background,
+        cellgrid,
         intensity_percentile,
         potential_percentile,
-        cellgrid_w,
-        cellgrid_h,
         screen_w,
         screen_h,
     );
@@ -120,23 +280,48 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
 
         if new_screen_w != screen_w
             || new_screen_h != screen_h
-            || potential_display_mode != old_potential_display_mode
+            || background != old_background
             || percentile != old_percentile
+            || cellgrid.field_version != old_field_version
         {
             (intensity_percentile, potential_percentile) = cellgrid.field_percentiles(percentile);
             texture = fill_texture_with_intensity(
-                potential_display_mode,
-                &cellgrid.stationary_charges,
+                background,
+                cellgrid,
                 intensity_percentile,
                 potential_percentile,
-                cellgrid_w,
-                cellgrid_h,
                 screen_w,
                 screen_h,
             );
             (screen_w, screen_h) = (new_screen_w, new_screen_h);
-            old_potential_display_mode = potential_display_mode;
+            old_background = background;
             old_percentile = percentile;
+            old_field_version = cellgrid.field_version;
+        }
+
+        // softening changes the field of the point charges, the radius also
+        // marks the cells next to the sources
+        if cellgrid.collision_model != old_collision_model
+            || cellgrid.collision_radius != old_collision_radius
+        {
+            cellgrid.refresh_field();
+            initial_energy = cellgrid.total_energy();
+            old_collision_model = cellgrid.collision_model;
+            old_collision_radius = cellgrid.collision_radius;
+        }
+
+        // energy drift is measured from the moment the integrator (or interaction / relativistic mode,
+        // field lookup) was selected
+        if cellgrid.integrator != old_integrator
+            || cellgrid.interactions != old_interactions
+            || cellgrid.relativistic != old_relativistic
+            || cellgrid.field_lookup != old_field_lookup
+        {
+            initial_energy = cellgrid.total_energy();
+            old_integrator = cellgrid.integrator;
+            old_interactions = cellgrid.interactions;
+            old_relativistic = cellgrid.relativistic;
+            old_field_lookup = cellgrid.field_lookup;
         }
 
         let start = Instant::now();
@@ -144,7 +329,8 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
             for _ in 0..steps_by_frame {
                 cellgrid.update_movable_charges(delta_t);
             }
-            time_elapsed += delta_t * steps_by_frame as f64;
+            // the background follows the time-varying charges
+            cellgrid.refresh_outdated_field();
         }
         let update_time = start.elapsed().as_micros();
 
@@ -154,8 +340,8 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
         let scale_y = screen_h / (cellgrid_h as f32);
 
         let (mouse_x, mouse_y) = mouse_position();
-        let mouse_x_scaled: f64 = (mouse_x / scale_x).into();
-        let mouse_y_scaled: f64 = (mouse_y / scale_y).into();
+        let mouse_x_scaled: f64 = (mouse_x / scale_x - 0.5).into();
+        let mouse_y_scaled: f64 = (mouse_y / scale_y - 0.5).into();
 
         // draw stretched texture
         draw_texture_ex(
@@ -169,15 +355,64 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
             },
         );
 
-        // display stationary charges
-        // for charge in &cellgrid.stationary_charges {
-        //     draw_circle(
-        //         charge.x as f32 * scale_x + scale_x / 2.0,
-        //         charge.y as f32 * scale_y + scale_y / 2.0,
-        //         5.0,
-        //         RED,
-        //     );
-        // }
+        // display stationary charges at their exact (sub-cell) positions and the
+        // charge distributions as shapes, anything outside of the grid is simply not visible
+        if draw_stationary {
+            for charge in &cellgrid.stationary_charges {
+                let charge_x_scaled = charge.x as f32 * scale_x + scale_x / 2.0;
+                let charge_y_scaled = charge.y as f32 * scale_y + scale_y / 2.0;
+                draw_circle_lines(
+                    charge_x_scaled,
+                    charge_y_scaled,
+                    4.0,
+                    1.5,
+                    if charge.q > 0. { RED } else { BLUE },
+                );
+            }
+            for distribution in &cellgrid.charge_distributions {
+                let color = if distribution.density() > 0. {
+                    RED
+                } else {
+                    BLUE
+                };
+                if let ChargeDistribution::Rectangle {
+                    x_min,
+                    x_max,
+                    y_min,
+                    y_max,
+                    ..
+                } = *distribution
+                {
+                    draw_rectangle_lines(
+                        x_min.min(x_max) as f32 * scale_x + scale_x / 2.0,
+                        y_min.min(y_max) as f32 * scale_y + scale_y / 2.0,
+                        (x_max - x_min).abs() as f32 * scale_x,
+                        (y_max - y_min).abs() as f32 * scale_y,
+                        1.5,
+                        color,
+                    );
+                }
+                for (start, end, _) in distribution.segments() {
+                    draw_line(
+                        start.x as f32 * scale_x + scale_x / 2.0,
+                        start.y as f32 * scale_y + scale_y / 2.0,
+                        end.x as f32 * scale_x + scale_x / 2.0,
+                        end.y as f32 * scale_y + scale_y / 2.0,
+                        1.5,
+                        color,
+                    );
+                }
+            }
+        }
+
+        // dielectrics are drawn in yellow and conductors in gray, whether or not
+        // the charges are shown
+        for dielectric in &cellgrid.dielectrics {
+            draw_shape(&dielectric.shape, scale_x, scale_y, 1.5, YELLOW);
+        }
+        for conductor in &cellgrid.conductors {
+            draw_shape(&conductor.shape, scale_x, scale_y, 2.0, LIGHTGRAY);
+        }
 
         // display movable charges and draw force vectors as arrows
         for charge in cellgrid.movable_charges.iter().filter(|c| c.should_move) {
@@ -220,17 +455,30 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
             if draw_details {
                 // show charge values above the charge (rounded to 2 decimal places), angle in degrees
                 draw_text(&format!("x: {:.2}, y: {:.2}, q: {:.2}, m: {:.2}, v: ({:.2}, {:.2} | {:.2}°), a: ({:.2}, {:.2} | {:.2}°)", charge.x, charge.y, charge.q, charge.m, charge.v.x, charge.v.y, charge.v.angle().to_degrees(), charge.a.x, charge.a.y, charge.a.angle().to_degrees()), charge_x_scaled, charge_y_scaled - 20.0, 10.0, WHITE);
+                draw_text(
+                    &format!(
+                        "γ: {:.6}, Ek: {:.3e} J",
+                        charge.gamma(),
+                        charge.kinetic_energy(cellgrid.relativistic)
+                    ),
+                    charge_x_scaled,
+                    charge_y_scaled - 10.0,
+                    10.0,
+                    WHITE,
+                );
+                let label = match &charge.name {
+                    Some(name) => format!("#{} {}", charge.id, name),
+                    None => format!("#{}", charge.id),
+                };
+                draw_text(&label, charge_x_scaled, charge_y_scaled - 30.0, 10.0, WHITE);
             }
         }
 
         // draw intensity vector at user's mouse position
         if draw_vectors {
-            let intensity = field_intensity_movable(
-                mouse_x_scaled,
-                mouse_y_scaled,
-                &cellgrid.stationary_charges,
-            )
-            .unwrap_or(XY { x: 0., y: 0. });
+            let intensity = cellgrid
+                .intensity_at(mouse_x_scaled, mouse_y_scaled)
+                .unwrap_or(XY { x: 0., y: 0. });
 
             let mut end_mouse = XY {
                 x: mouse_x,
@@ -286,7 +534,7 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
                             // charges that collided, charges that left the screen, etc.
                             ui.label("Upłynięty czas symulacji");
                             // print only the necessary zeros
-                            let stringified_time = format!("{:.16}", time_elapsed);
+                            let stringified_time = format!("{:.16}", cellgrid.time_elapsed);
                             let stringified_time = stringified_time
                                 .trim_end_matches('0')
                                 .trim_end_matches('.')
@@ -295,13 +543,13 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
                             ui.label(stringified_time);
                             ui.end_row();
                             ui.label("FPS");
-                            ui.label(&get_fps().to_string());
+                            ui.label(get_fps().to_string());
                             ui.end_row();
                             ui.label("Kroki na klatke");
-                            ui.label(&steps_by_frame.to_string());
+                            ui.label(steps_by_frame.to_string());
                             ui.end_row();
                             ui.label("Delta T na krok");
-                            ui.label(&delta_t.to_string());
+                            ui.label(delta_t.to_string());
                             ui.end_row();
                             ui.label("Delta T na klatkę");
                             let stringified_delta_t =
@@ -312,12 +560,29 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
                                 .trim_end_matches('.');
                             ui.label(stringified_delta_t.to_string());
                             ui.end_row();
+                            if cellgrid.integrator.is_adaptive() {
+                                ui.label("Najmniejszy krok adaptacyjny");
+                                let smallest_step = cellgrid
+                                    .movable_charges
+                                    .iter()
+                                    .filter(|c| c.should_move && c.adaptive_dt > 0.)
+                                    .map(|c| c.adaptive_dt)
+                                    .fold(f64::INFINITY, f64::min);
+                                ui.label(format!("{:e}", smallest_step));
+                                ui.end_row();
+                            }
+                            ui.label("Rozmiar siatki");
+                            ui.label(format!(
+                                "{}x{} (komórka {}m)",
+                                cellgrid_w, cellgrid_h, cellgrid.cell_size
+                            ));
+                            ui.end_row();
                             ui.label("Liczba ładunków ruchomych");
-                            ui.label(&cellgrid.movable_charges.len().to_string());
+                            ui.label(cellgrid.movable_charges.len().to_string());
                             ui.end_row();
                             ui.label("Liczba kolizji");
                             ui.label(
-                                &cellgrid
+                                cellgrid
                                     .movable_charges
                                     .iter()
                                     .filter(|&x| (x).collided)
@@ -325,14 +590,46 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
                                     .to_string(),
                             );
                             ui.end_row();
+                            ui.label("Liczba ładunków pochłoniętych na brzegach");
+                            ui.label(
+                                cellgrid
+                                    .movable_charges
+                                    .iter()
+                                    .filter(|&x| (x).escaped)
+                                    .count()
+                                    .to_string(),
+                            );
+                            ui.end_row();
+                            ui.label("Energia całkowita");
+                            let energy = cellgrid.total_energy();
+                            ui.label(format!("{:.6e}J", energy));
+                            ui.end_row();
+                            ui.label("Dryf energii");
+                            ui.label(format_energy_drift(initial_energy, energy));
+                            ui.end_row();
                             ui.label("Liczba ładunków stacjonarnych");
-                            ui.label(&cellgrid.stationary_charges.len().to_string());
+                            ui.label(cellgrid.stationary_charges.len().to_string());
                             ui.end_row();
+                            if !cellgrid.conductors.is_empty() {
+                                ui.label("Liczba przewodników");
+                                ui.label(cellgrid.conductors.len().to_string());
+                                ui.end_row();
+                            }
+                            if !cellgrid.dielectrics.is_empty() {
+                                ui.label("Liczba dielektryków");
+                                ui.label(cellgrid.dielectrics.len().to_string());
+                                ui.end_row();
+                            }
+                            if !cellgrid.external_fields.is_empty() {
+                                ui.label("Liczba pól zewnętrznych");
+                                ui.label(cellgrid.external_fields.len().to_string());
+                                ui.end_row();
+                            }
                             ui.label("Czas obliczeń na klatkę");
-                            ui.label(&format!("{}ms", update_time as f64 / 1000.0));
+                            ui.label(format!("{}ms", update_time as f64 / 1000.0));
                             ui.end_row();
                             ui.label("Czas renderowania");
-                            ui.label(&format!("{:.2}ms", get_frame_time() * 1000.0));
+                            ui.label(format!("{:.2}ms", get_frame_time() * 1000.0));
                             ui.end_row();
                         });
                 });
@@ -358,23 +655,94 @@ async fn macroquad_display(cellgrid: &mut CellGrid, delta_t: f64) {
                             ui.add(egui::DragValue::new(&mut steps_by_frame).speed(1.0));
                             ui.end_row();
                             ui.label("Delta T na krok");
-                            ui.add(egui::DragValue::new(&mut delta_t).speed(0.01));
+                            ui.add(
+                                egui::DragValue::new(&mut delta_t)
+                                    .speed(0.01)
+                                    .clamp_range(1e-15..=f64::MAX),
+                            );
+                            ui.end_row();
+                            ui.label("Metoda całkowania");
+                            egui::ComboBox::from_id_source("integrator")
+                                .selected_text(cellgrid.integrator.name())
+                                .show_ui(ui, |ui| {
+                                    for kind in IntegratorKind::ALL {
+                                        ui.selectable_value(
+                                            &mut cellgrid.integrator,
+                                            kind,
+                                            kind.name(),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+                            ui.label("Pole działające na ładunki");
+                            egui::ComboBox::from_id_source("field_lookup")
+                                .selected_text(cellgrid.field_lookup.name())
+                                .show_ui(ui, |ui| {
+                                    for lookup in FieldLookup::ALL {
+                                        // the direct sums don't include the conductors and dielectrics
+                                        if lookup == FieldLookup::Direct && cellgrid.grid_field {
+                                            continue;
+                                        }
+                                        ui.selectable_value(
+                                            &mut cellgrid.field_lookup,
+                                            lookup,
+                                            lookup.name(),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+                            ui.label("Model zderzeń");
+                            egui::ComboBox::from_id_source("collision_model")
+                                .selected_text(cellgrid.collision_model.name())
+                                .show_ui(ui, |ui| {
+                                    for model in CollisionModel::ALL {
+                                        ui.selectable_value(
+                                            &mut cellgrid.collision_model,
+                                            model,
+                                            model.name(),
+                                        );
+                                    }
+                                });
                             ui.end_row();
+                            ui.label("Promień zderzenia");
+                            ui.add(
+                                egui::DragValue::new(&mut cellgrid.collision_radius)
+                                    .speed(0.1)
+                                    .clamp_range(0.1..=50.0),
+                            );
+                            ui.end_row();
+                            ui.label("Oddziaływanie ładunków ruchomych");
+                            ui.add(toggle::toggle(&mut cellgrid.interactions));
+                            ui.end_row();
+                            ui.label("Tryb relatywistyczny");
+                            ui.add(toggle::toggle(&mut cellgrid.relativistic));
+                            ui.end_row();
+                            if cellgrid.integrator.is_adaptive() {
+                                ui.label("Tolerancja błędu");
+                                ui.add(
+                                    egui::DragValue::new(&mut cellgrid.tolerance)
+                                        .speed(1e-7)
+                                        .clamp_range(1e-12..=1.0),
+                                );
+                                ui.end_row();
+                            }
                             ui.label("Informacje o ładunkach");
                             ui.add(toggle::toggle(&mut draw_details));
                             ui.end_row();
                             ui.label("Pokaż wektory");
                             ui.add(toggle::toggle(&mut draw_vectors));
                             ui.end_row();
-                            ui.label(
-                                "Tło: ".to_owned()
-                                    + if potential_display_mode {
-                                        "potencjał"
-                                    } else {
-                                        "natężenie pola"
-                                    },
-                            );
-                            ui.add(toggle::toggle(&mut potential_display_mode));
+                            ui.label("Pokaż ładunki stacjonarne");
+                            ui.add(toggle::toggle(&mut draw_stationary));
+                            ui.end_row();
+                            ui.label("Tło");
+                            egui::ComboBox::from_id_source("background")
+                                .selected_text(background.name())
+                                .show_ui(ui, |ui| {
+                                    for kind in Background::ALL {
+                                        ui.selectable_value(&mut background, kind, kind.name());
+                                    }
+                                });
                             ui.end_row();
                             ui.label("Percentyl tła");
                             ui.add(egui::Slider::new(&mut percentile, 0.5..=0.999).text(""));
@@ -416,9 +784,17 @@ struct Args {
     #[arg(long, default_value_t = false)]
     bez_gui: bool,
 
-    /// Przyjęta delta dla symulacji
-    #[arg(short, long, default_value_t = 0.000001)]
-    delta_t: f64,
+    /// Plik scenariusza (TOML), domyślnie ładunki są wczytywane z plików ladunki_*.txt
+    #[arg(short, long)]
+    scenariusz: Option<String>,
+
+    /// Zapisz wczytany scenariusz (wraz z opcjami podanymi w linii poleceń) do pliku TOML i zakończ
+    #[arg(long)]
+    zapisz_scenariusz: Option<String>,
+
+    /// Przyjęta delta dla symulacji [domyślnie: 0.000001]
+    #[arg(short, long)]
+    delta_t: Option<f64>,
 
     /// (bez GUI) Czy symulacja powinna być przerwana gdy wszystkie ładunki opuszczą siatkę
     #[arg(long, default_value_t = false)]
@@ -432,35 +808,382 @@ struct Args {
     #[arg(long, default_value_t = false)]
     zapisz_ruch: bool,
 
-    /// (bez GUI) Maksymalna liczba kroków symulacji
-    #[arg(short, long, default_value_t = 10000)]
-    max_krokow: u32,
+    /// (bez GUI) Zapisuj tylko co N-ty krok ruchu ładunków (zdarzenia końcowe są zapisywane zawsze) [domyślnie: 1]
+    #[arg(long)]
+    co_ile_krokow: Option<usize>,
+
+    /// (bez GUI) Format zapisywanego pola i ruchu: tekstowy CSV, binarne tablice NumPy lub pliki VTK dla ParaView [domyślnie: csv]
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// (bez GUI, format vtk, z --zapisz-ruch) Zapisuj klatkę serii czasowej (output/grid.pvd, output/charges.pvd) co N kroków [domyślnie: 0 - bez klatek]
+    #[arg(long)]
+    klatki_co: Option<usize>,
+
+    /// (bez GUI) Maksymalna liczba kroków symulacji [domyślnie: 10000]
+    #[arg(short, long)]
+    max_krokow: Option<u32>,
+
+    /// Metoda całkowania równań ruchu [domyślnie: boris przy polu magnetycznym, w przeciwnym razie verlet]
+    #[arg(short, long, value_enum)]
+    integrator: Option<IntegratorKind>,
+
+    /// Względna tolerancja błędu dla adaptacyjnej metody całkowania (rk45) [domyślnie: 0.000001]
+    #[arg(short, long)]
+    tolerancja: Option<f64>,
+
+    /// Czy ładunki ruchome powinny oddziaływać także między sobą
+    #[arg(long, default_value_t = false)]
+    oddzialywania: bool,
+
+    /// Czy używać dynamiki relatywistycznej (pęd z czynnikiem Lorentza, prędkość zawsze poniżej c)
+    #[arg(long, default_value_t = false)]
+    relatywistycznie: bool,
+
+    /// Zachowanie ładunków ruchomych na wszystkich brzegach siatki (w scenariuszu można ustawić każdy brzeg osobno) [domyślnie: otwarty]
+    #[arg(long, value_enum)]
+    brzegi: Option<Boundary>,
+
+    /// Sposób wyznaczania pola działającego na ładunki ruchome (interpolacja pola z siatki jest szybsza) [domyślnie: bezposrednio]
+    #[arg(long, value_enum)]
+    pole_ladunkow: Option<FieldLookup>,
+
+    /// Porównaj trajektorie dla pola interpolowanego z siatki i sumy bezpośredniej, wypisz różnice i zakończ
+    #[arg(long, default_value_t = false)]
+    porownaj_interpolacje: bool,
+
+    /// Co dzieje się z ładunkiem ruchomym, który zbliży się do ładunku stacjonarnego [domyślnie: zatrzymanie]
+    #[arg(long, value_enum)]
+    model_zderzen: Option<CollisionModel>,
+
+    /// Promień zderzenia w komórkach, przy modelu zmiękczenia długość zmiękczenia [domyślnie: 2]
+    #[arg(long)]
+    promien_zderzenia: Option<f64>,
+
+    /// Czy liczyć pole na siatce (wraz z przewodnikami i dielektrykami) i interpolować je w położeniach ładunków
+    #[arg(long, default_value_t = false)]
+    solwer_poissona: bool,
+
+    /// Względna tolerancja iteracji SOR dla pola na siatce [domyślnie: 0.00000001]
+    #[arg(long)]
+    tolerancja_poissona: Option<f64>,
+
+    /// Czy przybliżać pole algorytmem Barnesa-Huta (szybsze dla wielu ładunków stacjonarnych)
+    #[arg(long, default_value_t = false)]
+    barnes_hut: bool,
+
+    /// Kąt otwarcia dla algorytmu Barnesa-Huta (0 = dokładna suma)
+    #[arg(long, default_value_t = 0.5)]
+    theta: f64,
+
+    /// Porównaj pole Barnesa-Huta z sumą bezpośrednią, wypisz maksymalny błąd względny i zakończ
+    #[arg(long, default_value_t = false)]
+    porownaj_barnes_hut: bool,
+
+    /// Liczba wątków używanych do obliczeń (domyślnie wszystkie rdzenie)
+    #[arg(long, visible_alias = "threads")]
+    watki: Option<usize>,
+
+    /// Szerokość siatki (liczba komórek) [domyślnie: 256]
+    #[arg(long)]
+    szerokosc: Option<usize>,
+
+    /// Wysokość siatki (liczba komórek) [domyślnie: 256]
+    #[arg(long)]
+    wysokosc: Option<usize>,
+
+    /// Rozmiar pojedynczej komórki siatki w metrach [domyślnie: 1]
+    #[arg(long)]
+    rozmiar_komorki: Option<f64>,
+}
+
+// Runs the scenario with the direct sums and with both interpolation methods
+// for max_steps steps, and prints how far the interpolated trajectories
+// deviate from the direct ones (in cells) and how long each run took
+fn compare_field_lookup(scenario: &Scenario) {
+    let delta_t = scenario.simulation.delta_t;
+    let max_steps = scenario.simulation.max_steps;
+    let mut runs: Vec<(FieldLookup, CellGrid, f64)> = FieldLookup::ALL
+        .into_iter()
+        .map(|lookup| {
+            let mut cellgrid = CellGrid::from_scenario(scenario, false);
+            cellgrid.field_lookup = lookup;
+            (lookup, cellgrid, 0.)
+        })
+        .collect();
+    if runs[0].1.grid_field {
+        eprintln!(
+            "{} suma bezpośrednia nie uwzględnia przewodników ani dielektryków, porównanie nie ma sensu",
+            "błąd:".red().bold()
+        );
+        return;
+    }
+    println!(
+        "Porównanie trajektorii dla {} ładunków przez {} kroków (metoda: {})",
+        scenario.movable_charges.len(),
+        max_steps,
+        runs[0].1.integrator.name()
+    );
+    for (lookup, cellgrid, time) in runs.iter_mut() {
+        if *lookup != FieldLookup::Direct {
+            let start = Instant::now();
+            cellgrid.populate_field();
+            *time += start.elapsed().as_secs_f64();
+        }
+    }
+
+    // largest deviation from the direct trajectory of any charge, and the
+    // number of charges which collided in only one of the runs
+    let mut max_deviation = vec![0f64; runs.len()];
+    for _ in 0..max_steps {
+        for (_, cellgrid, time) in runs.iter_mut() {
+            let start = Instant::now();
+            cellgrid.update_movable_charges(delta_t);
+            *time += start.elapsed().as_secs_f64();
+        }
+        let ((_, direct, _), interpolated) = runs.split_first().expect("brak metod do porównania");
+        for (k, (_, cellgrid, _)) in interpolated.iter().enumerate() {
+            for (a, b) in direct.movable_charges.iter().zip(&cellgrid.movable_charges) {
+                let deviation = ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt();
+                max_deviation[k + 1] = max_deviation[k + 1].max(deviation);
+            }
+        }
+    }
+
+    let direct = &runs[0].1;
+    for (k, (lookup, cellgrid, time)) in runs.iter().enumerate() {
+        let collisions = direct
+            .movable_charges
+            .iter()
+            .zip(&cellgrid.movable_charges)
+            .filter(|(a, b)| a.collided != b.collided)
+            .count();
+        println!(
+            "{}: czas {:.1}ms, maks. odchylenie {:.3e} komórek, różne zderzenia: {}",
+            lookup.name(),
+            time * 1000.,
+            max_deviation[k],
+            collisions
+        );
+    }
+}
+
+// prints all problems found in the input files, so they can be fixed at once
+fn print_scenario_errors(errors: &[ScenarioError]) {
+    for error in errors {
+        eprintln!(
+            "{} {}: {}",
+            "błąd:".red().bold(),
+            error.location().bold(),
+            error.message()
+        );
+    }
+    let summary = format!(
+        "Nie udało się wczytać scenariusza (liczba błędów: {})",
+        errors.len()
+    );
+    eprintln!("{}", summary.red());
+}
+
+impl Args {
+    // options given on the command line take precedence over the scenario file
+    fn apply_to(&self, scenario: &mut Scenario) {
+        if let Some(delta_t) = self.delta_t {
+            scenario.simulation.delta_t = delta_t;
+        }
+        if let Some(max_steps) = self.max_krokow {
+            scenario.simulation.max_steps = max_steps;
+        }
+        if let Some(integrator) = self.integrator {
+            scenario.simulation.integrator = Some(integrator);
+        }
+        if let Some(tolerance) = self.tolerancja {
+            scenario.simulation.tolerance = tolerance;
+        }
+        if let Some(width) = self.szerokosc {
+            scenario.grid.width = width;
+        }
+        if let Some(height) = self.wysokosc {
+            scenario.grid.height = height;
+        }
+        if let Some(cell_size) = self.rozmiar_komorki {
+            scenario.grid.cell_size = cell_size;
+        }
+        scenario.simulation.interactions |= self.oddzialywania;
+        scenario.simulation.relativistic |= self.relatywistycznie;
+        if let Some(boundary) = self.brzegi {
+            scenario.boundaries = Boundaries::all(boundary);
+        }
+        if let Some(field_lookup) = self.pole_ladunkow {
+            scenario.simulation.field_lookup = field_lookup;
+        }
+        if let Some(model) = self.model_zderzen {
+            scenario.simulation.collision_model = model;
+        }
+        if let Some(radius) = self.promien_zderzenia {
+            scenario.simulation.collision_radius = radius;
+        }
+        scenario.simulation.poisson_solver |= self.solwer_poissona;
+        if let Some(tolerance) = self.tolerancja_poissona {
+            scenario.simulation.poisson_tolerance = tolerance;
+        }
+        scenario.output.save_field |= self.zapisz_pole;
+        scenario.output.save_movement |= self.zapisz_ruch;
+        if let Some(every) = self.co_ile_krokow {
+            scenario.output.every_n_steps = every;
+        }
+        if let Some(format) = self.format {
+            scenario.output.format = format;
+        }
+        if let Some(every) = self.klatki_co {
+            scenario.output.frame_every = every;
+        }
+        scenario.output.stop_when_all_left |= self.zakoncz_po_opuszczeniu;
+    }
+
+    // options that are not a part of the scenario, reported together with the
+    // problems found in it
+    fn validate(&self) -> Vec<ScenarioError> {
+        let mut errors = Vec::new();
+        // theta = 0 is allowed, it gives the exact direct sum
+        if !self.theta.is_finite() || self.theta < 0. {
+            errors.push(ScenarioError::Setting {
+                key: "--theta".to_string(),
+                value: self.theta.to_string(),
+                requirement: "musi być skończona i nieujemna",
+            });
+        }
+        errors
+    }
 }
 
 #[macroquad::main("Symulacja")]
 async fn main() {
     let args = Args::parse();
 
+    if let Some(threads) = args.watki {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Nie można utworzyć puli wątków");
+    }
+
     ensure_files_exist();
 
-    // read charges from file
-    let mut cellgrid = CellGrid::new_from_file("ladunki_stacjonarne.txt", args.zapisz_ruch);
+    // read the scenario, or import the charges from the old text files
+    let scenario = match &args.scenariusz {
+        Some(path) => Scenario::from_file(path),
+        None => Scenario::from_text_files("ladunki_stacjonarne.txt", "ladunki_ruchome.txt"),
+    };
+    let mut scenario = scenario.unwrap_or_else(|errors| {
+        print_scenario_errors(&errors);
+        std::process::exit(1);
+    });
+    args.apply_to(&mut scenario);
+    let mut errors = scenario.validate().err().unwrap_or_default();
+    errors.extend(args.validate());
+    if !errors.is_empty() {
+        print_scenario_errors(&errors);
+        std::process::exit(1);
+    }
+    let integrator = scenario.integrator();
+    if !scenario.magnetic_fields.is_empty() && integrator != IntegratorKind::Boris {
+        eprintln!(
+            "{} metoda {} nie zachowuje prędkości w polu magnetycznym, tory cyklotronowe będą się rozkręcać spiralnie (zalecana metoda: {})",
+            "uwaga:".yellow().bold(),
+            integrator.name(),
+            "-i boris".to_string().bold()
+        );
+    }
+
+    if let Some(path) = &args.zapisz_scenariusz {
+        scenario.save_to_file(path);
+        println!("Zapisano scenariusz do pliku {}", path);
+        return;
+    }
+
+    let output = &scenario.output;
+    let mut cellgrid = CellGrid::from_scenario(&scenario, output.save_movement);
+    if output.frame_every > 0 && (output.format != OutputFormat::Vtk || !output.save_movement) {
+        eprintln!(
+            "{} klatki serii czasowej ({}) są zapisywane tylko z opcjami {} i {}, zostaną pominięte",
+            "uwaga:".yellow().bold(),
+            "--klatki-co".to_string().bold(),
+            "--format vtk".to_string().bold(),
+            "--zapisz-ruch".to_string().bold()
+        );
+    }
+    for source in cellgrid.non_periodic_sources() {
+        eprintln!(
+            "{} brzegi okresowe nie obejmują źródła: {} (działa bez obrazów okresowych)",
+            "uwaga:".yellow().bold(),
+            source
+        );
+    }
 
     println!("Odczytane ładunki:");
     for charge in &cellgrid.stationary_charges {
         println!("x: {}, y: {}, q: {}", charge.x, charge.y, charge.q);
     }
 
-    // calculate the field only for saving or gui background
-    if args.zapisz_pole || !args.bez_gui {
+    if args.barnes_hut || args.porownaj_barnes_hut {
+        cellgrid.use_barnes_hut(args.theta);
+    }
+
+    if args.porownaj_barnes_hut {
         let start = Instant::now();
-        cellgrid.populate_field();
+        let (intensity_error, potential_error) = cellgrid
+            .barnes_hut_error()
+            .expect("Drzewo Barnesa-Huta nie zostało zbudowane");
+        println!(
+            "Maksymalny błąd względny dla theta = {}: natężenie {:e}, potencjał {:e}",
+            args.theta, intensity_error, potential_error
+        );
+        let compare_time = start.elapsed().as_micros();
+        println!("Czas porównania: {}ms", compare_time as f64 / 1000.0);
+        return;
+    }
+
+    if args.porownaj_interpolacje {
+        compare_field_lookup(&scenario);
+        return;
+    }
+
+    // calculate the field only for saving or gui background, or when the
+    // charges are moved by the field interpolated from the grid
+    if output.save_field || !args.bez_gui || cellgrid.field_lookup != FieldLookup::Direct {
+        let start = Instant::now();
+        let solver = cellgrid.populate_field();
         let populate_time = start.elapsed().as_micros();
         // cellgrid.display_potential_color();
         println!("Czas obliczeń: {}ms", populate_time as f64 / 1000.0);
+        if let Some((iterations, converged)) = solver {
+            if converged {
+                println!("Pole na siatce: zbieżność po {} iteracjach SOR", iterations);
+            } else {
+                eprintln!(
+                    "{} pole na siatce nie osiągnęło zadanej tolerancji po {} iteracjach SOR",
+                    "uwaga:".yellow().bold(),
+                    iterations
+                );
+            }
+        }
 
-        if args.zapisz_pole {
-            cellgrid.save_grid_to_file("output/output_grid.csv");
+        if output.save_field {
+            match output.format {
+                OutputFormat::Csv => {
+                    cellgrid.save_grid_to_file("output/output_grid.csv");
+                    cellgrid.save_stationary_charges_to_file("output/stationary_charges.csv");
+                }
+                OutputFormat::Npy => {
+                    cellgrid.save_grid_to_npy("output");
+                    cellgrid.save_stationary_charges_to_npy("output/stationary_charges.npy");
+                }
+                OutputFormat::Vtk => {
+                    cellgrid.save_grid_to_vtk("output/output_grid.vtk");
+                    cellgrid.save_stationary_charges_to_vtk("output/stationary_charges.vtk");
+                }
+            }
         }
     }
 
@@ -494,19 +1217,15 @@ async fn main() {
     //         a,
     //         should_move: true,
     //         collided: false,
+    //         adaptive_dt: 0.,
     //     });
     // }
 
-    let movable_charges = MovableCharge::vec_from_file("ladunki_ruchome.txt");
-    for charge in movable_charges {
-        cellgrid.add_movable_charge(charge);
-    }
-
     println!();
 
     if args.bez_gui {
         // if there is neither save_field nor save_movement, just exit
-        if !args.zapisz_pole && !args.zapisz_ruch {
+        if !output.save_field && !output.save_movement {
             println!("Wybrano tryb bez interfejsu graficznego, ale nie wybrano żadnej z opcji zapisu! (wyniki nie zostaną zapisane)");
             println!(
                 "Aby zapisać pole, użyj opcji {}",
@@ -519,24 +1238,45 @@ async fn main() {
             return;
         }
 
-        if args.zapisz_pole {
-            println!("Zapisano pole do pliku output_grid.csv");
+        if output.save_field {
+            match output.format {
+                OutputFormat::Csv => println!("Zapisano pole do pliku output_grid.csv (ładunki stacjonarne do stationary_charges.csv)"),
+                OutputFormat::Npy => println!("Zapisano pole do plików q.npy, Ex.npy, Ey.npy, E.npy, V.npy i eps_r.npy (ładunki stacjonarne do stationary_charges.npy)"),
+                OutputFormat::Vtk => println!("Zapisano pole do pliku output_grid.vtk (ładunki stacjonarne do stationary_charges.vtk)"),
+            }
         }
 
-        if !args.zapisz_ruch {
+        if !output.save_movement {
             return;
         }
-        println!("Symulowanie przez max. {} kroków", args.max_krokow);
+        let delta_t = scenario.simulation.delta_t;
+        let max_steps = scenario.simulation.max_steps;
+        println!(
+            "Symulowanie przez max. {} kroków (metoda: {})",
+            max_steps,
+            cellgrid.integrator.name()
+        );
+        let initial_energy = cellgrid.total_energy();
+
+        // the time series for ParaView, the grid only if the field was calculated
+        let mut frames = (output.format == OutputFormat::Vtk && output.frame_every > 0)
+            .then(|| FrameSeries::new("output", output.frame_every, output.save_field));
+        if let Some(frames) = &mut frames {
+            frames.save_frame(&mut cellgrid);
+        }
 
         // simulation
         let start = Instant::now();
-        if args.zakoncz_po_opuszczeniu {
+        if output.stop_when_all_left {
             let (cellgrid_w, cellgrid_h) = cellgrid.get_dimensions();
             let cellgrid_w_f64 = cellgrid_w as f64;
             let cellgrid_h_f64 = cellgrid_h as f64;
 
-            'simulation: for _ in 0..args.max_krokow {
-                cellgrid.update_movable_charges(args.delta_t);
+            'simulation: for _ in 0..max_steps {
+                cellgrid.update_movable_charges(delta_t);
+                if let Some(frames) = &mut frames {
+                    frames.step(&mut cellgrid);
+                }
 
                 for charge in cellgrid.movable_charges.iter() {
                     if in_bounds(charge.x, charge.y, 0., cellgrid_w_f64, 0., cellgrid_h_f64) {
@@ -549,21 +1289,43 @@ async fn main() {
                 break 'simulation;
             }
         } else {
-            for _ in 0..args.max_krokow {
-                cellgrid.update_movable_charges(args.delta_t);
+            for _ in 0..max_steps {
+                cellgrid.update_movable_charges(delta_t);
+                if let Some(frames) = &mut frames {
+                    frames.step(&mut cellgrid);
+                }
             }
         }
         let update_time = start.elapsed().as_micros();
         println!("Czas obliczeń: {}ms", update_time as f64 / 1000.0);
 
-        // saving movement history to file
-        println!("Zapisywanie ruchu do pliku");
-        let start = Instant::now();
-        cellgrid.save_movement_history();
-        let save_time = start.elapsed().as_micros();
-        println!("Czas zapisu: {}ms", save_time as f64 / 1000.0);
+        let final_energy = cellgrid.total_energy();
+        println!(
+            "Energia początkowa: {:e}J, końcowa: {:e}J, dryf: {}",
+            initial_energy,
+            final_energy,
+            format_energy_drift(initial_energy, final_energy)
+        );
+
+        // the movement is written while simulating, only the rest of the buffers
+        // (and the VTK files, built from their side files) is left
+        cellgrid.flush_trajectories();
+        println!(
+            "Zapisano ruch ładunków do plików output/charge_*.{}",
+            output.format.extension()
+        );
+        if frames.is_some() {
+            println!(
+                "Zapisano serię czasową do pliku output/charges.pvd{} (klatki w output/frames)",
+                if output.save_field {
+                    " i output/grid.pvd"
+                } else {
+                    ""
+                }
+            );
+        }
     } else {
-        if args.zapisz_ruch {
+        if output.save_movement {
             eprintln!(
                 "Opcja {} nie jest obsługiwana w trybie graficznym!",
                 "--zapisz-ruch".to_string().bold()
@@ -576,6 +1338,6 @@ async fn main() {
         }
 
         // display gui
-        macroquad_display(&mut cellgrid, args.delta_t).await;
+        macroquad_display(&mut cellgrid, scenario.simulation.delta_t).await;
     }
 }
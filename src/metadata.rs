@@ -0,0 +1,185 @@
+use std::{fs, path::Path};
+
+use serde::Serialize;
+
+use crate::{integrator::IntegratorKind, scenario::Scenario};
+
+// name, unit and description of a column of an output file
+pub type Column = (&'static str, &'static str, &'static str);
+
+pub const GRID_COLUMNS: [Column; 8] = [
+    ("x", "m", "położenie środka komórki"),
+    ("y", "m", "położenie środka komórki"),
+    ("q", "C", "ładunek stacjonarny w komórce"),
+    ("Ex", "V/m", "natężenie pola"),
+    ("Ey", "V/m", "natężenie pola"),
+    ("E", "V/m", "wartość natężenia pola"),
+    ("V", "V", "potencjał"),
+    ("eps_r", "1", "względna przenikalność"),
+];
+
+pub const STATIONARY_COLUMNS: [Column; 3] = [
+    ("x", "m", "położenie ładunku"),
+    ("y", "m", "położenie ładunku"),
+    ("q", "C", "ładunek"),
+];
+
+pub const TRAJECTORY_COLUMNS: [Column; 14] = [
+    (
+        "krok",
+        "1",
+        "numer zapisanego stanu ładunku (przed decymacją)",
+    ),
+    ("t", "s", "czas symulacji"),
+    ("id", "1", "identyfikator ładunku"),
+    ("x", "m", "położenie"),
+    ("y", "m", "położenie"),
+    ("vx", "m/s", "prędkość"),
+    ("vy", "m/s", "prędkość"),
+    ("ax", "m/s^2", "przyspieszenie"),
+    ("ay", "m/s^2", "przyspieszenie"),
+    ("dt", "s", "czas od poprzedniego zapisanego wiersza"),
+    ("gamma", "1", "czynnik Lorentza"),
+    ("Ek", "J", "energia kinetyczna"),
+    (
+        "Ep",
+        "J",
+        "energia potencjalna w polu źródeł (bez innych ładunków ruchomych), NaN przy źródle",
+    ),
+    (
+        "zdarzenie",
+        "",
+        "krok, zderzenie, pochloniecie lub ucieczka (w plikach .npy i .vtk kody 0, 1, 2, 3)",
+    ),
+];
+
+// The arrays of the VTK files, the points are the positions of the cells or
+// the charges [m]. Vectors have the z component equal to 0.
+pub const VTK_GRID_ARRAYS: [Column; 4] = [
+    GRID_COLUMNS[2],
+    GRID_COLUMNS[6],
+    GRID_COLUMNS[7],
+    ("E", "V/m", "wektor natężenia pola (Ex, Ey)"),
+];
+
+pub const VTK_STATIONARY_ARRAYS: [Column; 1] = [STATIONARY_COLUMNS[2]];
+
+pub const VTK_TRAJECTORY_ARRAYS: [Column; 10] = [
+    TRAJECTORY_COLUMNS[0],
+    TRAJECTORY_COLUMNS[1],
+    TRAJECTORY_COLUMNS[2],
+    TRAJECTORY_COLUMNS[9],
+    TRAJECTORY_COLUMNS[10],
+    TRAJECTORY_COLUMNS[11],
+    TRAJECTORY_COLUMNS[12],
+    (
+        "zdarzenie",
+        "1",
+        "kod zdarzenia: 0 krok, 1 zderzenie, 2 pochloniecie, 3 ucieczka",
+    ),
+    ("v", "m/s", "wektor prędkości (vx, vy)"),
+    ("a", "m/s^2", "wektor przyspieszenia (ax, ay)"),
+];
+
+pub const VTK_MOVABLE_ARRAYS: [Column; 5] = [
+    TRAJECTORY_COLUMNS[2],
+    ("q", "C", "ładunek"),
+    ("v", "m/s", "wektor prędkości (vx, vy)"),
+    TRAJECTORY_COLUMNS[10],
+    TRAJECTORY_COLUMNS[11],
+];
+
+// the header row of a CSV file with the columns
+pub fn header(columns: &[Column]) -> String {
+    columns
+        .iter()
+        .map(|(name, _, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Settings of the run that produced the output files, written to the JSON
+// file next to every CSV file together with the description of its columns.
+#[derive(Serialize, Clone)]
+pub struct RunInfo {
+    // FNV-1a hash of the scenario (with the command line options applied), so
+    // results of the same scenario can be matched
+    #[serde(rename = "hash_scenariusza")]
+    pub scenario_hash: String,
+    pub delta_t: f64,
+    pub integrator: IntegratorKind,
+    #[serde(rename = "szerokosc")]
+    pub width: usize,
+    #[serde(rename = "wysokosc")]
+    pub height: usize,
+    // [m]
+    #[serde(rename = "rozmiar_komorki")]
+    pub cell_size: f64,
+}
+
+#[derive(Serialize)]
+struct Metadata<'a> {
+    #[serde(rename = "plik")]
+    file: &'a str,
+    #[serde(flatten)]
+    run: &'a RunInfo,
+    // simulation time the file was saved at [s], only for the snapshots
+    #[serde(rename = "czas", skip_serializing_if = "Option::is_none")]
+    time: Option<f64>,
+    #[serde(rename = "kolumny")]
+    columns: Vec<ColumnInfo<'a>>,
+}
+
+#[derive(Serialize)]
+struct ColumnInfo<'a> {
+    #[serde(rename = "nazwa")]
+    name: &'a str,
+    #[serde(rename = "jednostka")]
+    unit: &'a str,
+    #[serde(rename = "opis")]
+    description: &'a str,
+}
+
+impl RunInfo {
+    pub fn from_scenario(scenario: &Scenario) -> Self {
+        let contents = toml::to_string(scenario).expect("Nie można zapisać scenariusza");
+        RunInfo {
+            scenario_hash: format!("{:016x}", fnv1a(contents.as_bytes())),
+            delta_t: scenario.simulation.delta_t,
+            integrator: scenario.integrator(),
+            width: scenario.grid.width,
+            height: scenario.grid.height,
+            cell_size: scenario.grid.cell_size,
+        }
+    }
+
+    // writes the description of the file at `path` (its columns, or the array
+    // for .npy files) to the same path with the extension changed to .json
+    pub fn write_sidecar(&self, path: &str, columns: &[Column], time: Option<f64>) {
+        let metadata = Metadata {
+            file: path.rsplit('/').next().unwrap_or(path),
+            run: self,
+            time,
+            columns: columns
+                .iter()
+                .map(|&(name, unit, description)| ColumnInfo {
+                    name,
+                    unit,
+                    description,
+                })
+                .collect(),
+        };
+        let sidecar = Path::new(path).with_extension("json");
+        let contents =
+            serde_json::to_string_pretty(&metadata).expect("Nie można zapisać metadanych");
+        fs::write(&sidecar, contents)
+            .unwrap_or_else(|_| panic!("Nie można zapisać pliku {}", sidecar.display()));
+    }
+}
+
+// 64-bit FNV-1a, stable between runs and platforms (unlike the std hasher)
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -1,78 +1,111 @@
-use std::fs::{self};
-
 use crate::{
+    boundary::Periods,
     cellgrid::StationaryCharge,
-    lib::helpers::{K, XY},
+    lib::helpers::{K, SPEED_OF_LIGHT, XY},
+    scenario::{read_text_file, text_records, ScenarioError},
 };
 
+// default collision radius (in cells): a movable charge closer than that to a
+// stationary charge, distribution or another movable charge (in N-body mode)
+// is treated as collided, or it's the softening length of the Plummer model
+pub const COLLISION_DISTANCE: f64 = 2.;
+
+// in the relativistic mode, initial speeds (from the file or the classical
+// mode) are capped to this fraction of the speed of light
+pub const MAX_SPEED_FRACTION: f64 = 0.999_999;
+
 pub struct MovableCharge {
+    // stable identifier, assigned when the charge is added to the grid
+    pub id: usize,
+    // optional name from the scenario file
+    pub name: Option<String>,
     pub should_move: bool,
     pub collided: bool,
+    // absorbed at an edge of the grid
+    pub escaped: bool,
     pub x: f64,
     pub y: f64,
     pub q: f64,
     pub m: f64,
     pub v: XY<f64>,
     pub a: XY<f64>,
+    // step size suggested by the adaptive integrator (0 if not chosen yet)
+    pub adaptive_dt: f64,
+}
+
+// Lorentz factor for the velocity v [m/s], infinite (or NaN) if v >= c
+pub fn lorentz_factor(v: XY<f64>) -> f64 {
+    1. / (1. - (v.length() / SPEED_OF_LIGHT).powi(2)).sqrt()
+}
+
+// kinetic energy [J] of a charge with mass m [kg] moving with velocity v [m/s]
+pub fn kinetic_energy(m: f64, v: XY<f64>, relativistic: bool) -> f64 {
+    let v_sq = v.x.powi(2) + v.y.powi(2);
+    if relativistic {
+        // (γ - 1) m c^2, written so it doesn't lose precision for small speeds
+        let gamma = lorentz_factor(v);
+        m * gamma.powi(2) * v_sq / (gamma + 1.)
+    } else {
+        0.5 * m * v_sq
+    }
 }
 
 impl MovableCharge {
-    pub fn vec_from_file(path: &str) -> Vec<MovableCharge> {
-        // file format is
-        // <x> <y> <q> <m> <vx> <vy> <ax> <ay>
-
-        // read the file
-        let file = fs::read_to_string(path)
-            .unwrap_or_else(|_| panic!("Nie można odczytać pliku {}", path));
-        // split the file into lines
-        let lines = file.lines();
-        // create a vector to store the movable charges
+    pub fn gamma(&self) -> f64 {
+        lorentz_factor(self.v)
+    }
+
+    pub fn kinetic_energy(&self, relativistic: bool) -> f64 {
+        kinetic_energy(self.m, self.v, relativistic)
+    }
+
+    // file format is
+    // <x> <y> <q> <m> <vx> <vy> <ax> <ay>
+    pub fn vec_from_file(path: &str) -> Result<Vec<MovableCharge>, Vec<ScenarioError>> {
+        let contents = read_text_file(path).map_err(|e| vec![e])?;
+        let mut errors = Vec::new();
         let mut movable_charges = Vec::new();
-        // iterate over the lines
-        for (i, line) in lines.enumerate().filter(|(_, line)| !line.starts_with('#')) {
-            let words: Vec<&str> = line.split_whitespace().collect();
 
-            // check the number of words
-            if words.len() != 8 {
-                panic!("Nieprawidłowa liczba słów w linii {} pliku {}", i, path);
-            }
+        for record in text_records(path, &contents, 8, &mut errors) {
+            let values: Vec<Option<f64>> = ["x", "y", "q", "m", "vx", "vy", "ax", "ay"]
+                .iter()
+                .enumerate()
+                .map(|(i, field)| record.parse(i, field, &mut errors))
+                .collect();
+            // skip the line if any of the values is wrong (already reported)
+            let Some(values) = values.into_iter().collect::<Option<Vec<f64>>>() else {
+                continue;
+            };
 
-            let movable_charge = MovableCharge {
+            movable_charges.push(MovableCharge {
+                id: 0,
+                name: None,
                 should_move: true,
                 collided: false,
-                x: words[0].parse().unwrap_or_else(|_| {
-                    panic!("Nie można odczytać x w linii {} pliku {}", i, path)
-                }),
-                y: words[1].parse().unwrap_or_else(|_| {
-                    panic!("Nie można odczytać y w linii {} pliku {}", i, path)
-                }),
-                q: words[2].parse().unwrap_or_else(|_| {
-                    panic!("Nie można odczytać q w linii {} pliku {}", i, path)
-                }),
-                m: words[3].parse().unwrap_or_else(|_| {
-                    panic!("Nie można odczytać m w linii {} pliku {}", i, path)
-                }),
+                escaped: false,
+                adaptive_dt: 0.,
+                x: values[0],
+                y: values[1],
+                q: values[2],
+                m: values[3],
                 v: XY {
-                    x: words[4].parse().unwrap_or_else(|_| {
-                        panic!("Nie można odczytać vx w linii {} pliku {}", i, path)
-                    }),
-                    y: words[5].parse().unwrap_or_else(|_| {
-                        panic!("Nie można odczytać vy w linii {} pliku {}", i, path)
-                    }),
+                    x: values[4],
+                    y: values[5],
                 },
                 a: XY {
-                    x: words[6].parse().unwrap_or_else(|_| {
-                        panic!("Nie można odczytać ax w linii {} pliku {}", i, path)
-                    }),
-                    y: words[7].parse().unwrap_or_else(|_| {
-                        panic!("Nie można odczytać ay w linii {} pliku {}", i, path)
-                    }),
+                    x: values[6],
+                    y: values[7],
                 },
-            };
-            movable_charges.push(movable_charge);
+            });
         }
 
-        movable_charges
+        // lines with a wrong number of values are reported before the values
+        errors.sort_by_key(ScenarioError::line);
+        if errors.is_empty() {
+            Ok(movable_charges)
+        } else {
+            Err(errors)
+        }
     }
 }
 
@@ -80,23 +113,31 @@ impl MovableCharge {
 
 // This function calculates the field intensity at a point (x, y) caused by a
 // set of stationary charges. The function returns an XY struct containing the
-// field intnsity for x and y axis.
+// field intnsity for x and y axis. Along the periodic axes the nearest image
+// of every charge is used. With `softening` > 0 the field is softened (Plummer
+// model) instead of checking `min_distance`.
 pub fn field_intensity_movable(
     x: f64,
     y: f64,
     stationary_charges: &Vec<StationaryCharge>,
+    min_distance: f64,
+    softening: f64,
+    periods: Periods,
 ) -> Option<XY<f64>> {
     let mut intensity_xy = XY { x: 0.0, y: 0.0 };
     for stationary_charge in stationary_charges {
-        let r_sq =
-            (x - stationary_charge.x as f64).powi(2) + (y - stationary_charge.y as f64).powi(2);
+        let d = periods.wrap(XY {
+            x: x - stationary_charge.x,
+            y: y - stationary_charge.y,
+        });
+        let r_sq = d.x.powi(2) + d.y.powi(2) + softening.powi(2);
         let r = r_sq.sqrt();
 
         // If the distance between the given point and the stationary charge is
-        // less than 2, the field intensity is goes way too high for accurate calculations.
+        // less than min_distance, the field intensity is goes way too high for accurate calculations.
         // We return infinity in this case, which later on is interpreted as a
         // collision of charges.
-        if r < 2. {
+        if softening == 0. && r < min_distance {
             return None;
         }
 
@@ -115,13 +156,13 @@ pub fn field_intensity_movable(
         // caused by the given stationary charge and add it to the total intensity
         // vector.
         let factor = K * stationary_charge.q / (r_sq * r);
-        intensity_xy.x += factor * (x - stationary_charge.x as f64);
-        intensity_xy.y += factor * (y - stationary_charge.y as f64);
+        intensity_xy.x += factor * d.x;
+        intensity_xy.y += factor * d.y;
 
         // another way to calculate the intensity vector
         // get the angle of the intensity vector
         // let intensity = K * stationary_charge.q / (r_sq * r);
-        // let angle = (y - stationary_charge.y as f64).atan2(x - stationary_charge.x as f64);
+        // let angle = (y - stationary_charge.y).atan2(x - stationary_charge.x);
         // calculate the intensity vector using trigonometry
         // let result2 = XY {
         //     x: intensity * angle.cos(),
@@ -131,34 +172,35 @@ pub fn field_intensity_movable(
     Some(intensity_xy)
 }
 
+// This function calculates both the field intensity vector and the potential at
+// a point (x, y). Returns None if a stationary charge is closer than
+// `min_distance` (or exactly at the point), unless the field is softened.
 pub fn field_intensity_potential(
     x: f64,
     y: f64,
-    stationary_charges: &Vec<StationaryCharge>,
-) -> Option<(f64, f64)> {
-    let mut intensity = 0.0;
+    stationary_charges: &[StationaryCharge],
+    min_distance: f64,
+    softening: f64,
+    periods: Periods,
+) -> Option<(XY<f64>, f64)> {
+    let mut intensity = XY { x: 0.0, y: 0.0 };
     let mut potential = 0.0;
     for stationary_charge in stationary_charges {
-        let r_sq =
-            (x - stationary_charge.x as f64).powi(2) + (y - stationary_charge.y as f64).powi(2);
+        let d = periods.wrap(XY {
+            x: x - stationary_charge.x,
+            y: y - stationary_charge.y,
+        });
+        let r_sq = d.x.powi(2) + d.y.powi(2) + softening.powi(2);
         let r = r_sq.sqrt();
 
-        // If the distance between the given point and the stationary charge is
-        // less than 2, the field intensity is goes way too high for accurate calculations.
-        // We return infinity in this case, which later on is interpreted as a
-        // collision of charges.
-        if r == 0. {
+        if (softening == 0. && r < min_distance) || r == 0. {
             return None;
         }
 
-        // The electric field intensity (E) at a point caused by a stationary
-        // charge is given by the formula E = k * q / r^3, where k is a constant,
-        // q is the charge of the stationary charge, and r is the distance to the
-        // point. We calculate the intensity of the field at the given point
-        // caused by the given stationary charge and add it to the total intensity
-        // vector.
+        // E = k * q / r^3 * r_vec and V = k * q / r, same as in field_intensity_movable
         let factor = K * stationary_charge.q / r;
-        intensity += factor / r_sq;
+        intensity.x += factor / r_sq * d.x;
+        intensity.y += factor / r_sq * d.y;
         potential += factor
     }
     Some((intensity, potential))
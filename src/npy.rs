@@ -0,0 +1,120 @@
+use std::{
+    fs,
+    io::{BufWriter, Write},
+};
+
+// Length of the header written by `header` (magic string, version, length and
+// the padded dictionary). It's fixed, so the header of a file that is still
+// being written can be replaced once the number of rows is known.
+pub const HEADER_LEN: usize = 128;
+
+// Header of a version 1.0 .npy file with a C-ordered array of little endian
+// f64 values with the given shape.
+pub fn header(shape: &[usize]) -> Vec<u8> {
+    let shape = match shape {
+        [length] => format!("({},)", length),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut dictionary = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': {}, }}",
+        shape
+    );
+    // magic string (6 bytes), version (2) and the length of the dictionary
+    // (2), the dictionary is padded with spaces and ends with a newline
+    let padding = HEADER_LEN - 10 - dictionary.len() - 1;
+    dictionary.push_str(&" ".repeat(padding));
+    dictionary.push('\n');
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN);
+    bytes.extend_from_slice(b"\x93NUMPY\x01\x00");
+    bytes.extend_from_slice(&(dictionary.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(dictionary.as_bytes());
+    bytes
+}
+
+// appends the values to the data part of a .npy file
+pub fn write_values(writer: &mut impl Write, values: &[f64]) -> std::io::Result<()> {
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+// Saves the array with the given shape (row by row) to a .npy file
+pub fn save_array(path: &str, shape: &[usize], values: &[f64]) {
+    let file = fs::File::create(path).expect("Nie można utworzyć pliku");
+    let mut writer = BufWriter::new(file);
+    writer
+        .write_all(&header(shape))
+        .and_then(|_| write_values(&mut writer, values))
+        .and_then(|_| writer.flush())
+        .expect("Nie można zapisać do pliku");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the shape from a header written by `header`, checking the fixed fields
+    fn parse_header(bytes: &[u8]) -> Vec<usize> {
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let length = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!(10 + length, HEADER_LEN);
+        let dictionary = std::str::from_utf8(&bytes[10..HEADER_LEN]).unwrap();
+        assert!(dictionary.ends_with('\n'));
+        let dictionary = dictionary.trim_end();
+        let prefix = "{'descr': '<f8', 'fortran_order': False, 'shape': (";
+        let shape = dictionary
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix("), }"))
+            .unwrap();
+        shape
+            .split(',')
+            .map(str::trim)
+            .filter(|length| !length.is_empty())
+            .map(|length| length.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn header_has_a_fixed_length() {
+        for shape in [
+            vec![0],
+            vec![7],
+            vec![0, 14],
+            vec![64, 64, 8],
+            vec![usize::MAX, usize::MAX],
+        ] {
+            let bytes = header(&shape);
+            assert_eq!(bytes.len(), HEADER_LEN);
+            assert_eq!(parse_header(&bytes), shape);
+        }
+        // a 1-D shape needs the trailing comma of a Python tuple
+        assert!(String::from_utf8_lossy(&header(&[7])).contains("'shape': (7,)"));
+    }
+
+    #[test]
+    fn saved_array_can_be_read_back() {
+        let path = std::env::temp_dir().join("fizyka-projekt-tablica.npy");
+        let path = path.to_str().unwrap();
+        let values = [1.5, -2., f64::NAN, 1e-300, 0., 6.25];
+        save_array(path, &[2, 3], &values);
+
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 8 * values.len());
+        assert_eq!(parse_header(&bytes), [2, 3]);
+        let read: Vec<u64> = bytes[HEADER_LEN..]
+            .chunks(8)
+            .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()).to_bits())
+            .collect();
+        let expected: Vec<u64> = values.iter().map(|value| value.to_bits()).collect();
+        assert_eq!(read, expected);
+    }
+}
@@ -0,0 +1,29 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+// Format of the saved field and trajectories: text CSV files with headers,
+// binary NumPy arrays (full f64 precision, loaded with numpy.load) or legacy
+// VTK files for ParaView
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum OutputFormat {
+    #[value(name = "csv")]
+    #[serde(rename = "csv")]
+    Csv,
+    #[value(name = "npy")]
+    #[serde(rename = "npy")]
+    Npy,
+    #[value(name = "vtk")]
+    #[serde(rename = "vtk")]
+    Vtk,
+}
+
+impl OutputFormat {
+    // extension of the output files
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Npy => "npy",
+            OutputFormat::Vtk => "vtk",
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::f64::consts::PI;
+
+// upper bound on the number of SOR sweeps, the solver stops earlier once the
+// largest change in a sweep drops below the tolerance
+const MAX_ITERATIONS: usize = 100_000;
+
+pub struct PoissonSolution {
+    // correction in the cells, row by row (index y * w + x)
+    pub phi: Vec<f64>,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+// the neighbours of a cell (None outside of the grid) with the permittivities
+// of the faces between them, and the free charge term
+struct Stencil {
+    faces: [(Option<usize>, f64); 4],
+    source: f64,
+    total: f64,
+}
+
+// Solves ∇·(ε ∇(V + φ)) = ∇²V for the correction φ on a w x h grid, where V
+// is the potential of the free charges in vacuum (so the right hand side is
+// the free charge density) and ε the relative permittivity of the cells. In
+// vacuum it reduces to the Laplace equation ∇²φ = 0. The finite volume
+// stencil uses the harmonic mean of ε on the faces between the cells, so the
+// normal component of D is continuous across dielectric interfaces. Cells
+// with `fixed[i]` set keep that value (conductors), φ = 0 and ε = 1 just
+// outside of the grid. Cells with an infinite V (a charge exactly at the cell)
+// are kept at φ = 0. The iteration is successive over-relaxation (Gauss-Seidel
+// with the optimal factor for a square grid), `tolerance` is relative to the
+// largest |φ|.
+pub fn solve_poisson(
+    w: usize,
+    h: usize,
+    permittivity: &[f64],
+    potential: &[f64],
+    fixed: &[Option<f64>],
+    tolerance: f64,
+) -> PoissonSolution {
+    let mut phi: Vec<f64> = fixed.iter().map(|value| value.unwrap_or(0.)).collect();
+    let omega = 2. / (1. + (PI / w.max(h) as f64).sin());
+    let neighbour = |x: usize, y: usize, dx: isize, dy: isize| {
+        let (x, y) = (x as isize + dx, y as isize + dy);
+        if x < 0 || y < 0 || x >= w as isize || y >= h as isize {
+            None
+        } else {
+            Some(y as usize * w + x as usize)
+        }
+    };
+    let face = |a: f64, b: f64| 2. * a * b / (a + b);
+
+    // the stencil doesn't change during the iterations, so the face
+    // permittivities and the source term of every cell are computed once
+    let stencils: Vec<Option<Stencil>> = (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let i = y * w + x;
+            if fixed[i].is_some() || !potential[i].is_finite() {
+                return None;
+            }
+            let mut faces = [(None, 0.); 4];
+            let (mut total, mut source) = (0., 0.);
+            for (k, (dx, dy)) in [(-1, 0), (1, 0), (0, -1), (0, 1)].into_iter().enumerate() {
+                let j = neighbour(x, y, dx, dy);
+                // a dielectric touching a conductor fills the space up to its
+                // surface, so the face takes the permittivity of the free cell
+                let epsilon = match j {
+                    Some(j) if fixed[j].is_some() => permittivity[i],
+                    Some(j) => face(permittivity[i], permittivity[j]),
+                    None => face(permittivity[i], 1.),
+                };
+                // V outside of the grid is unknown, its normal derivative is
+                // taken as zero there
+                let difference = j.map_or(0., |j| potential[j] - potential[i]);
+                if difference.is_finite() {
+                    source += (epsilon - 1.) * difference;
+                }
+                faces[k] = (j, epsilon);
+                total += epsilon;
+            }
+            Some(Stencil {
+                faces,
+                source,
+                total,
+            })
+        })
+        .collect();
+
+    for iteration in 1..=MAX_ITERATIONS {
+        let mut max_change: f64 = 0.;
+        for (i, stencil) in stencils.iter().enumerate() {
+            let Some(stencil) = stencil else {
+                continue;
+            };
+            let sum: f64 = stencil
+                .faces
+                .iter()
+                .map(|&(j, epsilon)| j.map_or(0., |j| epsilon * phi[j]))
+                .sum();
+            let change = omega * ((sum + stencil.source) / stencil.total - phi[i]);
+            phi[i] += change;
+            max_change = max_change.max(change.abs());
+        }
+        let scale = phi.iter().fold(0., |max: f64, value| max.max(value.abs()));
+        if max_change <= tolerance * scale {
+            return PoissonSolution {
+                phi,
+                iterations: iteration,
+                converged: true,
+            };
+        }
+    }
+    PoissonSolution {
+        phi,
+        iterations: MAX_ITERATIONS,
+        converged: false,
+    }
+}
@@ -0,0 +1,794 @@
+use std::{fmt, fs, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boundary::Boundaries,
+    cellgrid::{ChargeSchedule, StationaryCharge},
+    charge_distribution::ChargeDistribution,
+    collision::CollisionModel,
+    conductor::Conductor,
+    dielectric::Dielectric,
+    external_field::ExternalField,
+    integrator::IntegratorKind,
+    interpolation::FieldLookup,
+    lib::helpers::XY,
+    magnetic_field::MagneticField,
+    movable_charge::{MovableCharge, COLLISION_DISTANCE},
+    output_format::OutputFormat,
+    waveform::Waveform,
+};
+
+// Scenario holds everything needed to run a simulation. It is read from a TOML
+// file, or imported from the old ladunki_stacjonarne.txt / ladunki_ruchome.txt
+// files (in which case the settings are taken from the command line).
+// The keys in the file are in Polish, same as the command line options.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(rename = "siatka")]
+    pub grid: GridSettings,
+    #[serde(rename = "symulacja")]
+    pub simulation: SimulationSettings,
+    #[serde(rename = "wyjscie")]
+    pub output: OutputSettings,
+    #[serde(rename = "brzegi")]
+    pub boundaries: Boundaries,
+    #[serde(rename = "ladunki_stacjonarne")]
+    pub stationary_charges: Vec<StationaryChargeEntry>,
+    #[serde(rename = "ladunki_ruchome")]
+    pub movable_charges: Vec<MovableChargeEntry>,
+    #[serde(rename = "ladunki_rozciagle")]
+    pub charge_distributions: Vec<ChargeDistribution>,
+    #[serde(rename = "pole_zewnetrzne")]
+    pub external_fields: Vec<ExternalField>,
+    #[serde(rename = "pole_magnetyczne")]
+    pub magnetic_fields: Vec<MagneticField>,
+    #[serde(rename = "przewodniki")]
+    pub conductors: Vec<Conductor>,
+    #[serde(rename = "dielektryki")]
+    pub dielectrics: Vec<Dielectric>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GridSettings {
+    #[serde(rename = "szerokosc")]
+    pub width: usize,
+    #[serde(rename = "wysokosc")]
+    pub height: usize,
+    // [m]
+    #[serde(rename = "rozmiar_komorki")]
+    pub cell_size: f64,
+}
+
+impl Default for GridSettings {
+    fn default() -> Self {
+        GridSettings {
+            width: 256,
+            height: 256,
+            cell_size: 1.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulationSettings {
+    // None selects the integrator by the fields in the scenario, see Scenario::integrator
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integrator: Option<IntegratorKind>,
+    pub delta_t: f64,
+    #[serde(rename = "max_krokow")]
+    pub max_steps: u32,
+    #[serde(rename = "tolerancja")]
+    pub tolerance: f64,
+    #[serde(rename = "oddzialywania")]
+    pub interactions: bool,
+    #[serde(rename = "relatywistycznie")]
+    pub relativistic: bool,
+    // compute the field on the grid (with the conductors and dielectrics) and
+    // interpolate it, always used when there are any in the scenario
+    #[serde(rename = "solwer_poissona")]
+    pub poisson_solver: bool,
+    #[serde(rename = "tolerancja_poissona")]
+    pub poisson_tolerance: f64,
+    // how the field acting on the movable charges is evaluated
+    #[serde(rename = "pole_ladunkow")]
+    pub field_lookup: FieldLookup,
+    // what happens to a movable charge coming too close to a point charge
+    #[serde(rename = "model_zderzen")]
+    pub collision_model: CollisionModel,
+    // collision radius or softening length [cells]
+    #[serde(rename = "promien_zderzenia")]
+    pub collision_radius: f64,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        SimulationSettings {
+            integrator: None,
+            delta_t: 0.000001,
+            max_steps: 10000,
+            tolerance: 1e-6,
+            interactions: false,
+            relativistic: false,
+            poisson_solver: false,
+            poisson_tolerance: 1e-8,
+            field_lookup: FieldLookup::Direct,
+            collision_model: CollisionModel::Stop,
+            collision_radius: COLLISION_DISTANCE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSettings {
+    #[serde(rename = "zapisz_pole")]
+    pub save_field: bool,
+    #[serde(rename = "zapisz_ruch")]
+    pub save_movement: bool,
+    #[serde(rename = "zakoncz_po_opuszczeniu")]
+    pub stop_when_all_left: bool,
+    // only every n-th step of the movement is saved
+    #[serde(rename = "co_ile_krokow")]
+    pub every_n_steps: usize,
+    pub format: OutputFormat,
+    // in the VTK format, a frame of the time series is saved every n steps
+    // (0 = no frames)
+    #[serde(rename = "klatki_co")]
+    pub frame_every: usize,
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        OutputSettings {
+            save_field: false,
+            save_movement: false,
+            stop_when_all_left: false,
+            every_n_steps: 1,
+            format: OutputFormat::Csv,
+            frame_every: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StationaryChargeEntry {
+    // in cells, may be fractional, negative or outside of the grid
+    pub x: f64,
+    pub y: f64,
+    pub q: f64,
+    // time dependence of the charge, q(t) = q * f(t)
+    #[serde(default, rename = "przebieg", skip_serializing_if = "Option::is_none")]
+    pub waveform: Option<Waveform>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MovableChargeEntry {
+    pub x: f64,
+    pub y: f64,
+    pub q: f64,
+    pub m: f64,
+    #[serde(default)]
+    pub vx: f64,
+    #[serde(default)]
+    pub vy: f64,
+    #[serde(default)]
+    pub ax: f64,
+    #[serde(default)]
+    pub ay: f64,
+    // shown in the GUI and used in the names of the output files
+    #[serde(default, rename = "nazwa", skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+// Problem found while loading a scenario or one of the text files. Line and
+// column numbers start at 1 and refer to the original file (comments included).
+#[derive(Debug)]
+pub enum ScenarioError {
+    Io {
+        file: String,
+        message: String,
+    },
+    FieldCount {
+        file: String,
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+    InvalidValue {
+        file: String,
+        line: usize,
+        column: usize,
+        field: &'static str,
+        value: String,
+    },
+    // syntax error or unknown / mistyped key in the TOML file
+    Toml {
+        file: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    // value outside of its allowed range, checked after the command line
+    // options are applied, so it's given by its key instead of the position
+    Setting {
+        key: String,
+        value: String,
+        requirement: &'static str,
+    },
+}
+
+impl ScenarioError {
+    pub fn line(&self) -> usize {
+        match self {
+            ScenarioError::Io { .. } | ScenarioError::Setting { .. } => 0,
+            ScenarioError::FieldCount { line, .. }
+            | ScenarioError::InvalidValue { line, .. }
+            | ScenarioError::Toml { line, .. } => *line,
+        }
+    }
+
+    // file:line:column, as far as it is known
+    pub fn location(&self) -> String {
+        match self {
+            ScenarioError::Io { file, .. } => file.clone(),
+            ScenarioError::Setting { key, .. } => key.clone(),
+            ScenarioError::FieldCount { file, line, .. } => format!("{}:{}", file, line),
+            ScenarioError::InvalidValue {
+                file, line, column, ..
+            }
+            | ScenarioError::Toml {
+                file, line, column, ..
+            } => format!("{}:{}:{}", file, line, column),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ScenarioError::Io { message, .. } => {
+                format!("nie można odczytać pliku ({})", message)
+            }
+            ScenarioError::FieldCount {
+                expected, found, ..
+            } => format!(
+                "nieprawidłowa liczba wartości w linii (oczekiwano {}, jest {})",
+                expected, found
+            ),
+            ScenarioError::InvalidValue { field, value, .. } => {
+                format!("nie można odczytać wartości {} z \"{}\"", field, value)
+            }
+            ScenarioError::Toml { message, .. } => message.trim_end().to_string(),
+            ScenarioError::Setting {
+                value, requirement, ..
+            } => format!("nieprawidłowa wartość {} ({})", value, requirement),
+        }
+    }
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location(), self.message())
+    }
+}
+
+// A single non-comment line of the old text files, split into whitespace
+// separated values (with their column numbers)
+pub struct TextRecord<'a> {
+    file: &'a str,
+    line: usize,
+    values: Vec<(usize, &'a str)>,
+}
+
+impl TextRecord<'_> {
+    // parses the value at `index`, on failure the error is added to `errors`
+    pub fn parse<T: FromStr>(
+        &self,
+        index: usize,
+        field: &'static str,
+        errors: &mut Vec<ScenarioError>,
+    ) -> Option<T> {
+        let (column, value) = self.values[index];
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            errors.push(ScenarioError::InvalidValue {
+                file: self.file.to_string(),
+                line: self.line,
+                column,
+                field,
+                value: value.to_string(),
+            });
+        }
+        parsed
+    }
+}
+
+pub fn read_text_file(file: &str) -> Result<String, ScenarioError> {
+    fs::read_to_string(file).map_err(|e| ScenarioError::Io {
+        file: file.to_string(),
+        message: e.to_string(),
+    })
+}
+
+// Splits the contents of a text file into records, skipping empty lines and
+// comments (starting with #). Lines with a wrong number of values are reported
+// in `errors` and skipped.
+pub fn text_records<'a>(
+    file: &'a str,
+    contents: &'a str,
+    value_count: usize,
+    errors: &mut Vec<ScenarioError>,
+) -> Vec<TextRecord<'a>> {
+    let mut records = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let values: Vec<(usize, &str)> = line
+            .split_whitespace()
+            // the values are subslices of the line, so the offset gives the column
+            .map(|value| {
+                let offset = value.as_ptr() as usize - line.as_ptr() as usize;
+                (line[..offset].chars().count() + 1, value)
+            })
+            .collect();
+
+        if values.len() != value_count {
+            errors.push(ScenarioError::FieldCount {
+                file: file.to_string(),
+                line: i + 1,
+                expected: value_count,
+                found: values.len(),
+            });
+            continue;
+        }
+        records.push(TextRecord {
+            file,
+            line: i + 1,
+            values,
+        });
+    }
+    records
+}
+
+// converts a byte offset into 1-based line and column numbers
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+impl Scenario {
+    pub fn from_file(path: &str) -> Result<Self, Vec<ScenarioError>> {
+        let contents = read_text_file(path).map_err(|e| vec![e])?;
+        toml::from_str(&contents).map_err(|e| {
+            let (line, column) = e
+                .span()
+                .map_or((1, 1), |span| line_column(&contents, span.start));
+            vec![ScenarioError::Toml {
+                file: path.to_string(),
+                line,
+                column,
+                message: e.message().to_string(),
+            }]
+        })
+    }
+
+    // imports the charges from the old whitespace separated text files,
+    // the settings are left at their default values. Errors from both files
+    // are reported together.
+    pub fn from_text_files(
+        stationary_path: &str,
+        movable_path: &str,
+    ) -> Result<Self, Vec<ScenarioError>> {
+        let stationary = StationaryCharge::vec_from_file(stationary_path);
+        let movable = MovableCharge::vec_from_file(movable_path);
+        let (stationary, movable) = match (stationary, movable) {
+            (Ok(stationary), Ok(movable)) => (stationary, movable),
+            (stationary, movable) => {
+                let mut errors = stationary.err().unwrap_or_default();
+                errors.extend(movable.err().unwrap_or_default());
+                return Err(errors);
+            }
+        };
+
+        let stationary_charges = stationary
+            .into_iter()
+            .map(|c| StationaryChargeEntry {
+                x: c.x,
+                y: c.y,
+                q: c.q,
+                waveform: None,
+            })
+            .collect();
+        let movable_charges = movable
+            .into_iter()
+            .map(|c| MovableChargeEntry {
+                x: c.x,
+                y: c.y,
+                q: c.q,
+                m: c.m,
+                vx: c.v.x,
+                vy: c.v.y,
+                ax: c.a.x,
+                ay: c.a.y,
+                name: None,
+            })
+            .collect();
+
+        Ok(Scenario {
+            stationary_charges,
+            movable_charges,
+            ..Default::default()
+        })
+    }
+
+    // The integrator chosen in the file or on the command line. By default it's
+    // Boris when there is a magnetic field (the other integrators don't keep the
+    // speed during gyration, so the orbits spiral out) and Velocity Verlet otherwise.
+    pub fn integrator(&self) -> IntegratorKind {
+        self.simulation
+            .integrator
+            .unwrap_or(if self.magnetic_fields.is_empty() {
+                IntegratorKind::Verlet
+            } else {
+                IntegratorKind::Boris
+            })
+    }
+
+    // Checks the values that can be read but not simulated (after the command
+    // line options are applied), all of the problems are reported together
+    pub fn validate(&self) -> Result<(), Vec<ScenarioError>> {
+        let mut errors = Vec::new();
+        let mut check = |valid: bool, key: String, value: String, requirement| {
+            if !valid {
+                errors.push(ScenarioError::Setting {
+                    key,
+                    value,
+                    requirement,
+                });
+            }
+        };
+
+        // the interpolation needs at least two cells in each direction
+        let grid = &self.grid;
+        let interpolated = self.simulation.field_lookup != FieldLookup::Direct
+            || self.simulation.poisson_solver
+            || !self.conductors.is_empty()
+            || !self.dielectrics.is_empty();
+        let (min_size, requirement) = if interpolated {
+            (2, "co najmniej 2 przy polu interpolowanym z siatki")
+        } else {
+            (1, "co najmniej 1")
+        };
+        for (key, size) in [
+            ("siatka.szerokosc", grid.width),
+            ("siatka.wysokosc", grid.height),
+        ] {
+            check(
+                size >= min_size,
+                key.to_string(),
+                size.to_string(),
+                requirement,
+            );
+        }
+        for (key, value) in [
+            ("siatka.rozmiar_komorki", grid.cell_size),
+            ("symulacja.delta_t", self.simulation.delta_t),
+            ("symulacja.tolerancja", self.simulation.tolerance),
+            (
+                "symulacja.promien_zderzenia",
+                self.simulation.collision_radius,
+            ),
+        ] {
+            check(
+                value.is_finite() && value > 0.,
+                key.to_string(),
+                value.to_string(),
+                "musi być skończona i dodatnia",
+            );
+        }
+        // the accelerations are divided by the mass
+        for (i, charge) in self.movable_charges.iter().enumerate() {
+            check(
+                charge.m.is_finite() && charge.m > 0.,
+                format!("ladunki_ruchome[{}].m", i),
+                charge.m.to_string(),
+                "masa musi być skończona i dodatnia",
+            );
+        }
+        // the solver divides by the permittivity at the faces of the cells
+        for (i, dielectric) in self.dielectrics.iter().enumerate() {
+            check(
+                dielectric.permittivity.is_finite() && dielectric.permittivity > 0.,
+                format!("dielektryki[{}].przenikalnosc", i),
+                dielectric.permittivity.to_string(),
+                "przenikalność musi być skończona i dodatnia",
+            );
+        }
+        // the ramp and the table are divided by the differences of their times
+        for (i, charge) in self.stationary_charges.iter().enumerate() {
+            match &charge.waveform {
+                Some(Waveform::Ramp { start, end, .. }) => check(
+                    end > start,
+                    format!("ladunki_stacjonarne[{}].przebieg.koniec", i),
+                    end.to_string(),
+                    "koniec rampy musi być późniejszy niż początek",
+                ),
+                Some(Waveform::Table { points }) => {
+                    let times: Vec<f64> = points.iter().map(|point| point[0]).collect();
+                    check(
+                        times.windows(2).all(|pair| pair[1] > pair[0]),
+                        format!("ladunki_stacjonarne[{}].przebieg.punkty", i),
+                        format!("{:?}", times),
+                        "czasy punktów muszą ściśle rosnąć",
+                    );
+                }
+                _ => {}
+            }
+        }
+        check(
+            self.output.every_n_steps >= 1,
+            "wyjscie.co_ile_krokow".to_string(),
+            self.output.every_n_steps.to_string(),
+            "co najmniej 1",
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn save_to_file(&self, path: &str) {
+        let contents = toml::to_string_pretty(self).expect("Nie można zapisać scenariusza");
+        fs::write(path, contents).unwrap_or_else(|_| panic!("Nie można zapisać pliku {}", path));
+    }
+}
+
+impl StationaryChargeEntry {
+    pub fn to_charge(&self) -> StationaryCharge {
+        let schedule = self.waveform.as_ref().map(|waveform| ChargeSchedule {
+            amplitude: self.q,
+            waveform: waveform.clone(),
+            absorbed: 0.,
+        });
+        StationaryCharge {
+            x: self.x,
+            y: self.y,
+            q: self
+                .waveform
+                .as_ref()
+                .map_or(self.q, |waveform| self.q * waveform.value(0.)),
+            schedule,
+        }
+    }
+}
+
+impl MovableChargeEntry {
+    pub fn to_charge(&self) -> MovableCharge {
+        MovableCharge {
+            id: 0,
+            name: self.name.clone(),
+            should_move: true,
+            collided: false,
+            escaped: false,
+            adaptive_dt: 0.,
+            x: self.x,
+            y: self.y,
+            q: self.q,
+            m: self.m,
+            v: XY {
+                x: self.vx,
+                y: self.vy,
+            },
+            a: XY {
+                x: self.ax,
+                y: self.ay,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // writes `contents` to a file in the temporary directory, returns its path
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("fizyka-projekt-{}", name));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn messages(errors: &[ScenarioError]) -> Vec<String> {
+        errors.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn text_records_skip_comments_and_report_columns() {
+        let contents = "# x y q\n\n  # wcięty komentarz\n 1  2 3\nż\t5 6\n7 8\n";
+        let mut errors = Vec::new();
+        let records = text_records("plik.txt", contents, 3, &mut errors);
+
+        let lines: Vec<usize> = records.iter().map(|r| r.line).collect();
+        assert_eq!(lines, [4, 5]);
+        assert_eq!(records[0].values, [(2, "1"), (5, "2"), (7, "3")]);
+        // columns are counted in characters, not bytes
+        assert_eq!(records[1].values, [(1, "ż"), (3, "5"), (5, "6")]);
+        assert_eq!(
+            messages(&errors),
+            ["plik.txt:6: nieprawidłowa liczba wartości w linii (oczekiwano 3, jest 2)"]
+        );
+
+        let value: Option<f64> = records[1].parse(0, "x", &mut errors);
+        assert!(value.is_none());
+        assert_eq!(records[1].parse::<f64>(2, "q", &mut errors), Some(6.));
+        assert_eq!(
+            messages(&errors[1..]),
+            ["plik.txt:5:1: nie można odczytać wartości x z \"ż\""]
+        );
+    }
+
+    #[test]
+    fn movable_charges_report_every_error() {
+        let path = temp_file(
+            "ladunki_ruchome.txt",
+            "# x y q m vx vy ax ay\n\
+             1 2 3 4 5 6 7 8\n\
+             \n\
+             1 2 abc 4 5 6 7 8\n\
+             1 2 3\n\
+             1\tx\t3 4 5 6 7 y\n\
+             ż 2 3 4 5 6 7 x\n",
+        );
+        let errors = MovableCharge::vec_from_file(&path).err().unwrap();
+        assert_eq!(
+            messages(&errors),
+            [
+                format!("{}:4:5: nie można odczytać wartości q z \"abc\"", path),
+                format!(
+                    "{}:5: nieprawidłowa liczba wartości w linii (oczekiwano 8, jest 3)",
+                    path
+                ),
+                format!("{}:6:3: nie można odczytać wartości y z \"x\"", path),
+                format!("{}:6:15: nie można odczytać wartości ay z \"y\"", path),
+                format!("{}:7:1: nie można odczytać wartości x z \"ż\"", path),
+                format!("{}:7:15: nie można odczytać wartości ay z \"x\"", path),
+            ]
+        );
+    }
+
+    #[test]
+    fn stationary_charges_are_read() {
+        let path = temp_file("ladunki_stacjonarne.txt", "# x y q\n1 2.5 3e-9\n-4 5 -1\n");
+        let charges = StationaryCharge::vec_from_file(&path).unwrap();
+        let values: Vec<(f64, f64, f64)> = charges.iter().map(|c| (c.x, c.y, c.q)).collect();
+        assert_eq!(values, [(1., 2.5, 3e-9), (-4., 5., -1.)]);
+
+        let path = temp_file("ladunki_stacjonarne_bledne.txt", "1,5 2 abc\n");
+        let errors = StationaryCharge::vec_from_file(&path).err().unwrap();
+        assert_eq!(
+            messages(&errors),
+            [
+                format!("{}:1:1: nie można odczytać wartości x z \"1,5\"", path),
+                format!("{}:1:7: nie można odczytać wartości q z \"abc\"", path),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_file_is_reported() {
+        let path = std::env::temp_dir().join("fizyka-projekt-brak.txt");
+        let path = path.to_str().unwrap();
+        let errors = MovableCharge::vec_from_file(path).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0], ScenarioError::Io { file, .. } if file == path));
+        assert_eq!(errors[0].location(), path);
+    }
+
+    #[test]
+    fn toml_errors_point_at_the_key() {
+        let path = temp_file(
+            "scenariusz.toml",
+            "[siatka]\nszerokosc = 10\n  nieznany = 1\n",
+        );
+        let errors = Scenario::from_file(&path).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].location(), format!("{}:3:3", path));
+        assert!(errors[0].message().contains("nieznany"));
+    }
+
+    #[test]
+    fn settings_are_reported_by_key() {
+        let mut scenario = Scenario::default();
+        scenario.grid.width = 0;
+        scenario.simulation.delta_t = f64::NAN;
+        scenario.simulation.collision_radius = 0.;
+        scenario.output.every_n_steps = 0;
+        let errors = scenario.validate().err().unwrap();
+        assert_eq!(
+            messages(&errors),
+            [
+                "siatka.szerokosc: nieprawidłowa wartość 0 (co najmniej 1)",
+                "symulacja.delta_t: nieprawidłowa wartość NaN (musi być skończona i dodatnia)",
+                "symulacja.promien_zderzenia: nieprawidłowa wartość 0 (musi być skończona i dodatnia)",
+                "wyjscie.co_ile_krokow: nieprawidłowa wartość 0 (co najmniej 1)",
+            ]
+        );
+
+        // a single cell is enough only for the direct sum
+        let mut scenario = Scenario::default();
+        scenario.grid.height = 1;
+        assert!(scenario.validate().is_ok());
+        scenario.simulation.poisson_solver = true;
+        let errors = scenario.validate().err().unwrap();
+        assert_eq!(
+            messages(&errors),
+            ["siatka.wysokosc: nieprawidłowa wartość 1 (co najmniej 2 przy polu interpolowanym z siatki)"]
+        );
+    }
+
+    #[test]
+    fn boris_is_the_default_with_a_magnetic_field() {
+        let mut scenario: Scenario = toml::from_str("").unwrap();
+        assert_eq!(scenario.integrator(), IntegratorKind::Verlet);
+
+        let with_field = "[[pole_magnetyczne]]\ntyp = \"jednorodne\"\nbz = 0.01\n";
+        scenario = toml::from_str(with_field).unwrap();
+        assert_eq!(scenario.integrator(), IntegratorKind::Boris);
+
+        // an explicit choice is kept
+        scenario = toml::from_str(&format!(
+            "{}[symulacja]\nintegrator = \"rk4\"\n",
+            with_field
+        ))
+        .unwrap();
+        assert_eq!(scenario.integrator(), IntegratorKind::Rk4);
+    }
+
+    #[test]
+    fn waveform_times_are_checked() {
+        let mut scenario = Scenario::default();
+        let waveforms = [
+            Waveform::Ramp {
+                from: 0.,
+                to: 1.,
+                start: 2e-6,
+                end: 1e-6,
+            },
+            Waveform::Table {
+                points: vec![[0., 1.], [1e-6, 2.], [1e-6, 0.]],
+            },
+            Waveform::Table {
+                points: vec![[0., 1.], [1e-6, 2.]],
+            },
+        ];
+        for waveform in waveforms {
+            scenario.stationary_charges.push(StationaryChargeEntry {
+                x: 1.,
+                y: 1.,
+                q: 1e-9,
+                waveform: Some(waveform),
+            });
+        }
+        let errors = scenario.validate().err().unwrap();
+        assert_eq!(
+            messages(&errors),
+            [
+                "ladunki_stacjonarne[0].przebieg.koniec: nieprawidłowa wartość 0.000001 \
+                 (koniec rampy musi być późniejszy niż początek)",
+                "ladunki_stacjonarne[1].przebieg.punkty: nieprawidłowa wartość [0.0, 1e-6, 1e-6] \
+                 (czasy punktów muszą ściśle rosnąć)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+// Region of the plane taken by a conductor or a dielectric, positions are given
+// in cells, same as the charges. In the scenario file `typ` selects the shape.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "typ", deny_unknown_fields)]
+pub enum Shape {
+    #[serde(rename = "prostokat")]
+    Rectangle {
+        x_min: f64,
+        x_max: f64,
+        y_min: f64,
+        y_max: f64,
+    },
+    #[serde(rename = "kolo")]
+    Disk { x: f64, y: f64, r: f64 },
+}
+
+impl Shape {
+    // whether the point (x, y) (in cells) lies inside the shape
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        match *self {
+            Shape::Rectangle {
+                x_min,
+                x_max,
+                y_min,
+                y_max,
+            } => {
+                x >= x_min.min(x_max)
+                    && x <= x_min.max(x_max)
+                    && y >= y_min.min(y_max)
+                    && y <= y_min.max(y_max)
+            }
+            Shape::Disk { x: cx, y: cy, r } => (x - cx).powi(2) + (y - cy).powi(2) <= r * r,
+        }
+    }
+}
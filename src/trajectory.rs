@@ -0,0 +1,565 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    lib::helpers::XY,
+    metadata::{header, RunInfo, TRAJECTORY_COLUMNS, VTK_TRAJECTORY_ARRAYS},
+    movable_charge::{kinetic_energy, lorentz_factor, MovableCharge},
+    npy,
+    output_format::OutputFormat,
+    vtk::{self, TableArray},
+};
+
+// the buffered rows are written to disk at least this often, so an
+// interrupted run only loses the last moment of the trajectories
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// the arrays of the VTK trajectories (VTK_TRAJECTORY_ARRAYS) as the columns of
+// the rows of the side files
+const VTK_TABLE_ARRAYS: [TableArray; 10] = [
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[0].0,
+        columns: &[0],
+    },
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[1].0,
+        columns: &[1],
+    },
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[2].0,
+        columns: &[2],
+    },
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[3].0,
+        columns: &[9],
+    },
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[4].0,
+        columns: &[10],
+    },
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[5].0,
+        columns: &[11],
+    },
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[6].0,
+        columns: &[12],
+    },
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[7].0,
+        columns: &[13],
+    },
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[8].0,
+        columns: &[5, 6],
+    },
+    TableArray {
+        name: VTK_TRAJECTORY_ARRAYS[9].0,
+        columns: &[7, 8],
+    },
+];
+
+// MovementStep is a single state of a movable charge, written to its trajectory file
+pub struct MovementStep {
+    // simulation time of the state [s]
+    pub t: f64,
+    pub x: f64,
+    pub y: f64,
+    pub v: XY<f64>,
+    pub a: XY<f64>,
+    // length of the step that led to this state
+    pub dt: f64,
+    pub event: MovementEvent,
+}
+
+// What a row of the trajectory records. The charge stops after the terminal
+// events, which are recorded with dt = 0.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MovementEvent {
+    Step,
+    // stopped after coming too close to a source
+    Collision,
+    // absorbed by a stationary charge
+    Absorption,
+    // absorbed at an edge of the grid
+    Escape,
+}
+
+impl MovementEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            MovementEvent::Step => "krok",
+            MovementEvent::Collision => "zderzenie",
+            MovementEvent::Absorption => "pochloniecie",
+            MovementEvent::Escape => "ucieczka",
+        }
+    }
+
+    // the event in the numeric (.npy) files
+    fn code(&self) -> f64 {
+        match self {
+            MovementEvent::Step => 0.,
+            MovementEvent::Collision => 1.,
+            MovementEvent::Absorption => 2.,
+            MovementEvent::Escape => 3.,
+        }
+    }
+}
+
+struct ChargeFile {
+    writer: BufWriter<fs::File>,
+    // states recorded so far, only every `every`-th step is written
+    steps: usize,
+    // time since the last written row [s]
+    pending_dt: f64,
+    // rows written so far, needed for the shape in the .npy header
+    rows: usize,
+    // path of the file, for VTK the rows go to the side file [path].part
+    // until the end of the run (the points have to be followed by the lines)
+    path: String,
+    // title of the VTK file
+    title: String,
+}
+
+impl ChargeFile {
+    // builds the VTK file from the rows in the side file, as a polyline through
+    // the positions of the charge, and removes the side file
+    fn write_vtk(mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        let part = self.writer.get_ref();
+        let mut output = BufWriter::new(fs::File::create(&self.path)?);
+        vtk::write_polyline(
+            &mut output,
+            &self.title,
+            self.rows,
+            &[3, 4],
+            &VTK_TABLE_ARRAYS,
+            |columns, write| {
+                let mut part = part;
+                part.seek(SeekFrom::Start(0))?;
+                let mut reader = BufReader::new(part);
+                let mut row = [0u8; 14 * 8];
+                for _ in 0..self.rows {
+                    reader.read_exact(&mut row)?;
+                    for &i in columns {
+                        let bytes = row[8 * i..8 * (i + 1)].try_into().unwrap();
+                        write(f64::from_le_bytes(bytes))?;
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        output.flush()?;
+        fs::remove_file(format!("{}.part", self.path))
+    }
+}
+
+// Writes the trajectories of the movable charges while the simulation runs,
+// one file per charge (keyed by its ID), instead of keeping them in memory.
+pub struct TrajectoryWriter {
+    directory: String,
+    // only every n-th step is written, the terminal events always are
+    every: usize,
+    format: OutputFormat,
+    run_info: RunInfo,
+    files: BTreeMap<usize, ChargeFile>,
+    last_flush: Instant,
+}
+
+impl TrajectoryWriter {
+    pub fn new(directory: &str, every: usize, format: OutputFormat, run_info: RunInfo) -> Self {
+        TrajectoryWriter {
+            directory: directory.to_string(),
+            every: every.max(1),
+            format,
+            run_info,
+            files: BTreeMap::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    // creates the file of the charge: [directory]/charge_[id].csv, or
+    // [directory]/charge_[id]_[name].csv for named charges (.npy for the
+    // binary format), with its header and the JSON description next to it
+    pub fn add_charge(&mut self, charge: &MovableCharge) {
+        let extension = self.format.extension();
+        let path = match &charge.name {
+            Some(name) => format!(
+                "{}/charge_{}_{}.{}",
+                self.directory,
+                charge.id,
+                file_name_part(name),
+                extension
+            ),
+            None => format!("{}/charge_{}.{}", self.directory, charge.id, extension),
+        };
+        let file = match self.format {
+            // the side file is read back at the end of the run
+            OutputFormat::Vtk => fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(format!("{}.part", path)),
+            _ => fs::File::create(&path),
+        }
+        .expect("Nie można utworzyć pliku");
+        let mut file = ChargeFile {
+            writer: BufWriter::new(file),
+            steps: 0,
+            pending_dt: 0.,
+            rows: 0,
+            path: path.clone(),
+            title: format!("Ruch ładunku {}", charge.id),
+        };
+        // the .npy header is rewritten with the number of rows on every flush,
+        // the VTK side files are raw rows of little endian values
+        match self.format {
+            OutputFormat::Csv => writeln!(file.writer, "{}", header(&TRAJECTORY_COLUMNS)),
+            OutputFormat::Npy => file
+                .writer
+                .write_all(&npy::header(&[0, TRAJECTORY_COLUMNS.len()])),
+            OutputFormat::Vtk => Ok(()),
+        }
+        .expect("Nie można zapisać do pliku");
+        let columns: &[_] = match self.format {
+            OutputFormat::Vtk => &VTK_TRAJECTORY_ARRAYS,
+            _ => &TRAJECTORY_COLUMNS,
+        };
+        self.run_info.write_sidecar(&path, columns, None);
+        self.files.insert(charge.id, file);
+    }
+
+    // Counts a state of the charge, returns whether it should be written. The
+    // terminal events are always written.
+    pub fn record(&mut self, id: usize, event: MovementEvent, dt: f64) -> bool {
+        let Some(file) = self.files.get_mut(&id) else {
+            return false;
+        };
+        file.pending_dt += dt;
+        file.steps += 1;
+        event != MovementEvent::Step || file.steps % self.every == 0
+    }
+
+    // Writes a recorded state of the charge, `potential` is the potential of
+    // the sources at the charge [V]. With decimation, dt of a row is the time
+    // since the previous written row.
+    pub fn write(
+        &mut self,
+        charge: &MovableCharge,
+        step: &MovementStep,
+        potential: f64,
+        relativistic: bool,
+    ) {
+        let cell_size = self.run_info.cell_size;
+        let format = self.format;
+        let Some(file) = self.files.get_mut(&charge.id) else {
+            return;
+        };
+        let values = [
+            file.steps as f64,
+            step.t,
+            charge.id as f64,
+            step.x * cell_size,
+            step.y * cell_size,
+            step.v.x,
+            step.v.y,
+            step.a.x,
+            step.a.y,
+            file.pending_dt,
+            lorentz_factor(step.v),
+            kinetic_energy(charge.m, step.v, relativistic),
+            charge.q * potential,
+            step.event.code(),
+        ];
+        match format {
+            OutputFormat::Csv => writeln!(
+                file.writer,
+                // positions in metres, times and energies in scientific notation,
+                // the rest with 6 decimal places, the event closes the row
+                "{}, {:e}, {}, {:e}, {:e}, {:.6}, {:.6}, {:.6}, {:.6}, {:e}, {:.6}, {:e}, {:e}, {}",
+                file.steps,
+                values[1],
+                charge.id,
+                values[3],
+                values[4],
+                values[5],
+                values[6],
+                values[7],
+                values[8],
+                values[9],
+                values[10],
+                values[11],
+                values[12],
+                step.event.name()
+            ),
+            OutputFormat::Npy | OutputFormat::Vtk => npy::write_values(&mut file.writer, &values),
+        }
+        .expect("Nie można zapisać do pliku");
+        file.pending_dt = 0.;
+        file.rows += 1;
+
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    // writes all of the buffered rows to disk, the .npy files get the header
+    // with their current number of rows, so they can be loaded at any time
+    pub fn flush(&mut self) {
+        for file in self.files.values_mut() {
+            match self.format {
+                OutputFormat::Csv | OutputFormat::Vtk => file.writer.flush(),
+                OutputFormat::Npy => {
+                    let shape = [file.rows, TRAJECTORY_COLUMNS.len()];
+                    file.writer.flush().and_then(|_| {
+                        let output = file.writer.get_mut();
+                        output
+                            .seek(SeekFrom::Start(0))
+                            .and_then(|_| output.write_all(&npy::header(&shape)))
+                            .and_then(|_| output.seek(SeekFrom::End(0)))
+                            .map(|_| ())
+                    })
+                }
+            }
+            .expect("Nie można zapisać do pliku");
+        }
+        self.last_flush = Instant::now();
+    }
+
+    // writes the rest of the rows at the end of the run and builds the VTK
+    // files from their side files
+    pub fn finish(&mut self) {
+        self.flush();
+        if self.format == OutputFormat::Vtk {
+            for file in std::mem::take(&mut self.files).into_values() {
+                file.write_vtk().expect("Nie można zapisać do pliku");
+            }
+        }
+    }
+}
+
+// the name of a charge made safe to use in a file name
+fn file_name_part(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenario::Scenario;
+
+    fn charge() -> MovableCharge {
+        MovableCharge {
+            id: 7,
+            name: None,
+            should_move: true,
+            collided: false,
+            escaped: false,
+            x: 0.,
+            y: 0.,
+            q: 1e-9,
+            m: 1e-10,
+            v: XY::zero(),
+            a: XY::zero(),
+            adaptive_dt: 0.,
+        }
+    }
+
+    fn step(x: f64, dt: f64, event: MovementEvent) -> MovementStep {
+        MovementStep {
+            t: 0.,
+            x,
+            y: 0.,
+            v: XY::zero(),
+            a: XY::zero(),
+            dt,
+            event,
+        }
+    }
+
+    // Ten steps of 1 ms with every third one written, then a collision: the
+    // written rows cover the skipped steps and the terminal row is kept
+    #[test]
+    fn decimated_rows_sum_the_skipped_steps() {
+        let directory = std::env::temp_dir().join("fizyka-projekt-decymacja");
+        fs::create_dir_all(&directory).unwrap();
+        let mut run_info = RunInfo::from_scenario(&Scenario::default());
+        run_info.cell_size = 1.;
+        let mut writer =
+            TrajectoryWriter::new(directory.to_str().unwrap(), 3, OutputFormat::Csv, run_info);
+        let charge = charge();
+        writer.add_charge(&charge);
+        let steps = (1..=10)
+            .map(|k| step(k as f64, 1e-3, MovementEvent::Step))
+            .chain([step(10., 0., MovementEvent::Collision)]);
+        for step in steps {
+            if writer.record(charge.id, step.event, step.dt) {
+                writer.write(&charge, &step, 0., false);
+            }
+        }
+        writer.flush();
+
+        let contents = fs::read_to_string(directory.join("charge_7.csv")).unwrap();
+        let rows: Vec<Vec<&str>> = contents
+            .lines()
+            .skip(1)
+            .map(|row| row.split(", ").collect())
+            .collect();
+        let column =
+            |i: usize| -> Vec<f64> { rows.iter().map(|row| row[i].parse().unwrap()).collect() };
+        assert_eq!(column(0), [3., 6., 9., 11.]);
+        assert_eq!(column(3), [3., 6., 9., 10.]);
+        let events: Vec<&str> = rows.iter().map(|row| row[13]).collect();
+        assert_eq!(events, ["krok", "krok", "krok", "zderzenie"]);
+        for (dt, expected) in column(9).into_iter().zip([3e-3, 3e-3, 3e-3, 1e-3]) {
+            assert!((dt - expected).abs() < 1e-15, "{} != {}", dt, expected);
+        }
+    }
+
+    // The header and every row of the file have the columns described in the
+    // sidecar, in the same order
+    #[test]
+    fn header_matches_the_columns() {
+        let directory = std::env::temp_dir().join("fizyka-projekt-naglowek");
+        fs::create_dir_all(&directory).unwrap();
+        let run_info = RunInfo::from_scenario(&Scenario::default());
+        let mut writer =
+            TrajectoryWriter::new(directory.to_str().unwrap(), 1, OutputFormat::Csv, run_info);
+        let charge = charge();
+        writer.add_charge(&charge);
+        for step in [
+            step(1., 1e-3, MovementEvent::Step),
+            step(1., 0., MovementEvent::Escape),
+        ] {
+            if writer.record(charge.id, step.event, step.dt) {
+                writer.write(&charge, &step, 0., false);
+            }
+        }
+        writer.flush();
+
+        let contents = fs::read_to_string(directory.join("charge_7.csv")).unwrap();
+        let mut lines = contents.lines();
+        assert_eq!(
+            lines.next(),
+            Some("krok, t, id, x, y, vx, vy, ax, ay, dt, gamma, Ek, Ep, zdarzenie")
+        );
+        let names: Vec<&str> = TRAJECTORY_COLUMNS.iter().map(|column| column.0).collect();
+        assert_eq!(header(&TRAJECTORY_COLUMNS), names.join(", "));
+        let rows: Vec<&str> = lines.collect();
+        assert_eq!(rows.len(), 2);
+        for row in rows {
+            assert_eq!(row.split(", ").count(), TRAJECTORY_COLUMNS.len());
+        }
+
+        let sidecar = fs::read_to_string(directory.join("charge_7.json")).unwrap();
+        let sidecar: serde_json::Value = serde_json::from_str(&sidecar).unwrap();
+        let described: Vec<&str> = sidecar["kolumny"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|column| column["nazwa"].as_str().unwrap())
+            .collect();
+        assert_eq!(described, names);
+        assert_eq!(sidecar["plik"], "charge_7.csv");
+    }
+
+    // The shape in the header follows the rows on every flush, the header
+    // keeps its length so the rows already written stay in place
+    #[test]
+    fn npy_shape_is_rewritten_on_flush() {
+        let directory = std::env::temp_dir().join("fizyka-projekt-npy");
+        fs::create_dir_all(&directory).unwrap();
+        let run_info = RunInfo::from_scenario(&Scenario::default());
+        let mut writer =
+            TrajectoryWriter::new(directory.to_str().unwrap(), 1, OutputFormat::Npy, run_info);
+        let charge = charge();
+        writer.add_charge(&charge);
+        let path = directory.join("charge_7.npy");
+        let columns = TRAJECTORY_COLUMNS.len();
+
+        let mut write_rows = |count: usize| {
+            for k in 0..count {
+                let step = step(k as f64, 1e-3, MovementEvent::Step);
+                if writer.record(charge.id, step.event, step.dt) {
+                    writer.write(&charge, &step, 0., false);
+                }
+            }
+            writer.flush();
+            fs::read(&path).unwrap()
+        };
+        for (count, rows) in [(3, 3), (4, 7)] {
+            let bytes = write_rows(count);
+            assert_eq!(bytes[..npy::HEADER_LEN], npy::header(&[rows, columns]));
+            assert_eq!(bytes.len(), npy::HEADER_LEN + 8 * rows * columns);
+        }
+        // the first value of every row is the number of the recorded state
+        let bytes = fs::read(&path).unwrap();
+        let first: Vec<f64> = (0..7)
+            .map(|row| {
+                let start = npy::HEADER_LEN + 8 * row * columns;
+                f64::from_le_bytes(bytes[start..start + 8].try_into().unwrap())
+            })
+            .collect();
+        assert_eq!(first, [1., 2., 3., 4., 5., 6., 7.]);
+    }
+
+    // The rows streamed to the side file become a polyline with a point per
+    // written row at the end of the run, the side file is removed
+    #[test]
+    fn vtk_polyline_is_built_from_the_side_file() {
+        let directory = std::env::temp_dir().join("fizyka-projekt-vtk");
+        fs::create_dir_all(&directory).unwrap();
+        let mut run_info = RunInfo::from_scenario(&Scenario::default());
+        run_info.cell_size = 1.;
+        let mut writer =
+            TrajectoryWriter::new(directory.to_str().unwrap(), 2, OutputFormat::Vtk, run_info);
+        let charge = charge();
+        writer.add_charge(&charge);
+        // every second of the 8 steps and the terminal row: 5 points
+        let steps = (1..=8)
+            .map(|k| step(k as f64, 1e-3, MovementEvent::Step))
+            .chain([step(8.5, 0., MovementEvent::Escape)]);
+        for step in steps {
+            if writer.record(charge.id, step.event, step.dt) {
+                writer.write(&charge, &step, 0., false);
+            }
+        }
+        writer.finish();
+        assert!(!directory.join("charge_7.vtk.part").exists());
+
+        let bytes = fs::read(directory.join("charge_7.vtk")).unwrap();
+        let find = |text: &str| {
+            let at = bytes
+                .windows(text.len())
+                .position(|window| window == text.as_bytes())
+                .unwrap_or_else(|| panic!("brak {:?}", text));
+            at + text.len()
+        };
+        let be_f64 = |at: usize| f64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+        let be_i32 = |at: usize| i32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+
+        let points = find("POINTS 5 double\n");
+        let x: Vec<f64> = (0..5).map(|i| be_f64(points + 24 * i)).collect();
+        assert_eq!(x, [2., 4., 6., 8., 8.5]);
+        assert!((0..5).all(|i| be_f64(points + 24 * i + 16) == 0.));
+
+        let lines = find("LINES 1 6\n");
+        let line: Vec<i32> = (0..6).map(|i| be_i32(lines + 4 * i)).collect();
+        assert_eq!(line, [5, 0, 1, 2, 3, 4]);
+        find("POINT_DATA 5\n");
+        assert_eq!(bytes.windows(5).filter(|w| w == b"LINES").count(), 1);
+    }
+}
@@ -0,0 +1,377 @@
+use std::{
+    fs,
+    io::{self, BufWriter, Write},
+};
+
+use crate::{cellgrid::CellGrid, lib::helpers::XY};
+
+// A named array of values at the points of a VTK data set, either scalars or
+// 3-component vectors (z = 0 in the plane of the grid).
+pub struct DataArray {
+    pub name: &'static str,
+    pub components: usize,
+    pub values: Vec<f64>,
+}
+
+impl DataArray {
+    pub fn scalars(name: &'static str, values: impl IntoIterator<Item = f64>) -> Self {
+        DataArray {
+            name,
+            components: 1,
+            values: values.into_iter().collect(),
+        }
+    }
+
+    pub fn vectors(name: &'static str, values: impl IntoIterator<Item = XY<f64>>) -> Self {
+        DataArray {
+            name,
+            components: 3,
+            values: values
+                .into_iter()
+                .flat_map(|value| [value.x, value.y, 0.])
+                .collect(),
+        }
+    }
+}
+
+// An array taken from the columns of a table with a row per point: a single
+// column for scalars, a pair of columns (x, y) for vectors
+pub struct TableArray {
+    pub name: &'static str,
+    pub columns: &'static [usize],
+}
+
+// Legacy VTK files are written in the binary variant (big endian), it keeps the
+// full precision and, unlike the ASCII one, can hold NaN and infinite values.
+fn legacy_header(writer: &mut impl Write, title: &str, dataset: &str) -> io::Result<()> {
+    // the title is limited to a single line of 256 characters
+    let title: String = title.chars().take(255).collect();
+    write!(
+        writer,
+        "# vtk DataFile Version 3.0\n{}\nBINARY\nDATASET {}\n",
+        title, dataset
+    )
+}
+
+fn legacy_point_data(writer: &mut impl Write, arrays: &[DataArray]) -> io::Result<()> {
+    let Some(points) = arrays
+        .first()
+        .map(|array| array.values.len() / array.components)
+    else {
+        return Ok(());
+    };
+    if points == 0 {
+        return Ok(());
+    }
+    writeln!(writer, "POINT_DATA {}", points)?;
+    for array in arrays {
+        if array.components == 1 {
+            writeln!(
+                writer,
+                "SCALARS {} double 1\nLOOKUP_TABLE default",
+                array.name
+            )?;
+        } else {
+            writeln!(writer, "VECTORS {} double", array.name)?;
+        }
+        for value in &array.values {
+            writer.write_all(&value.to_be_bytes())?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+// Writes a legacy STRUCTURED_POINTS file, the arrays are given row by row
+// starting at the point (0, 0), `spacing` is the distance between the points [m]
+pub fn write_structured_points(
+    writer: &mut impl Write,
+    title: &str,
+    (width, height): (usize, usize),
+    spacing: f64,
+    arrays: &[DataArray],
+) -> io::Result<()> {
+    legacy_header(writer, title, "STRUCTURED_POINTS")?;
+    writeln!(
+        writer,
+        "DIMENSIONS {} {} 1\nORIGIN 0 0 0\nSPACING {:e} {:e} {:e}",
+        width, height, spacing, spacing, spacing
+    )?;
+    legacy_point_data(writer, arrays)
+}
+
+// Writes a legacy POLYDATA file with the points [m] in the plane z = 0 as
+// separate vertices
+pub fn write_polydata(
+    writer: &mut impl Write,
+    title: &str,
+    points: &[XY<f64>],
+    arrays: &[DataArray],
+) -> io::Result<()> {
+    legacy_header(writer, title, "POLYDATA")?;
+    writeln!(writer, "POINTS {} double", points.len())?;
+    for point in points {
+        for value in [point.x, point.y, 0.] {
+            writer.write_all(&value.to_be_bytes())?;
+        }
+    }
+    writeln!(writer)?;
+    if !points.is_empty() {
+        // every vertex as the number of its points (1) and its index
+        writeln!(writer, "VERTICES {} {}", points.len(), 2 * points.len())?;
+        for i in 0..points.len() as i32 {
+            writer.write_all(&1i32.to_be_bytes())?;
+            writer.write_all(&i.to_be_bytes())?;
+        }
+        writeln!(writer)?;
+    }
+    legacy_point_data(writer, arrays)
+}
+
+// Writes a legacy POLYDATA file with a single polyline through the `len`
+// points of a table that doesn't have to fit in memory. `read(columns, write)`
+// passes the given columns of every row of the table, in order, to `write`,
+// it's called once for the points (`position`, the columns of x and y [m]) and
+// once for every array.
+pub fn write_polyline(
+    writer: &mut impl Write,
+    title: &str,
+    len: usize,
+    position: &[usize],
+    arrays: &[TableArray],
+    mut read: impl FnMut(&[usize], &mut dyn FnMut(f64) -> io::Result<()>) -> io::Result<()>,
+) -> io::Result<()> {
+    // the vectors (and the points) get z = 0 after every pair of values
+    let mut write_columns = |writer: &mut dyn Write, columns: &[usize]| {
+        let mut count = 0usize;
+        read(columns, &mut |value| {
+            writer.write_all(&value.to_be_bytes())?;
+            count += 1;
+            if columns.len() == 2 && count.is_multiple_of(2) {
+                writer.write_all(&0f64.to_be_bytes())?;
+            }
+            Ok(())
+        })?;
+        writeln!(writer)
+    };
+
+    legacy_header(writer, title, "POLYDATA")?;
+    writeln!(writer, "POINTS {} double", len)?;
+    write_columns(writer, position)?;
+    if len == 0 {
+        return Ok(());
+    }
+    // the line as the number of its points followed by their indices
+    writeln!(writer, "LINES 1 {}", len + 1)?;
+    writer.write_all(&(len as i32).to_be_bytes())?;
+    for i in 0..len as i32 {
+        writer.write_all(&i.to_be_bytes())?;
+    }
+    writeln!(writer)?;
+
+    writeln!(writer, "POINT_DATA {}", len)?;
+    for array in arrays {
+        if array.columns.len() == 1 {
+            writeln!(
+                writer,
+                "SCALARS {} double 1\nLOOKUP_TABLE default",
+                array.name
+            )?;
+        } else {
+            writeln!(writer, "VECTORS {} double", array.name)?;
+        }
+        write_columns(writer, array.columns)?;
+    }
+    Ok(())
+}
+
+// The XML formats (needed for the .pvd collections) with the arrays inline as
+// base64, each preceded by its length in bytes.
+fn xml_data_array(
+    writer: &mut impl Write,
+    name: Option<&str>,
+    components: usize,
+    values: &[f64],
+) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(4 + values.len() * 8);
+    bytes.extend_from_slice(&((values.len() * 8) as u32).to_le_bytes());
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    let name = name.map_or(String::new(), |name| format!(" Name=\"{}\"", name));
+    writeln!(
+        writer,
+        "<DataArray type=\"Float64\"{} NumberOfComponents=\"{}\" format=\"binary\">{}</DataArray>",
+        name,
+        components,
+        base64(&bytes)
+    )
+}
+
+fn xml_point_data(writer: &mut impl Write, arrays: &[DataArray]) -> io::Result<()> {
+    writeln!(writer, "<PointData>")?;
+    for array in arrays {
+        xml_data_array(writer, Some(array.name), array.components, &array.values)?;
+    }
+    writeln!(writer, "</PointData>")
+}
+
+fn xml_file(writer: &mut impl Write, kind: &str) -> io::Result<()> {
+    writeln!(
+        writer,
+        "<?xml version=\"1.0\"?>\n<VTKFile type=\"{}\" version=\"0.1\" byte_order=\"LittleEndian\" header_type=\"UInt32\">",
+        kind
+    )
+}
+
+// Writes a .vti file (XML image data), the same grid as write_structured_points
+pub fn write_image_data(
+    writer: &mut impl Write,
+    (width, height): (usize, usize),
+    spacing: f64,
+    arrays: &[DataArray],
+) -> io::Result<()> {
+    let extent = format!("0 {} 0 {} 0 0", width.max(1) - 1, height.max(1) - 1);
+    xml_file(writer, "ImageData")?;
+    writeln!(
+        writer,
+        "<ImageData WholeExtent=\"{}\" Origin=\"0 0 0\" Spacing=\"{:e} {:e} {:e}\">\n<Piece Extent=\"{}\">",
+        extent, spacing, spacing, spacing, extent
+    )?;
+    xml_point_data(writer, arrays)?;
+    writeln!(writer, "</Piece>\n</ImageData>\n</VTKFile>")
+}
+
+// Writes a .vtp file (XML polydata) with the points [m] as separate vertices
+pub fn write_vertices(
+    writer: &mut impl Write,
+    points: &[XY<f64>],
+    arrays: &[DataArray],
+) -> io::Result<()> {
+    xml_file(writer, "PolyData")?;
+    writeln!(
+        writer,
+        "<PolyData>\n<Piece NumberOfPoints=\"{0}\" NumberOfVerts=\"{0}\" NumberOfLines=\"0\" NumberOfStrips=\"0\" NumberOfPolys=\"0\">",
+        points.len()
+    )?;
+    writeln!(writer, "<Points>")?;
+    let coordinates: Vec<f64> = points.iter().flat_map(|p| [p.x, p.y, 0.]).collect();
+    xml_data_array(writer, None, 3, &coordinates)?;
+    writeln!(writer, "</Points>")?;
+    // the vertices are given as ASCII indices, they are just 0..n
+    let indices = (0..points.len())
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let offsets = (1..=points.len())
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(" ");
+    writeln!(
+        writer,
+        "<Verts>\n<DataArray type=\"Int64\" Name=\"connectivity\" format=\"ascii\">{}</DataArray>\n<DataArray type=\"Int64\" Name=\"offsets\" format=\"ascii\">{}</DataArray>\n</Verts>",
+        indices, offsets
+    )?;
+    xml_point_data(writer, arrays)?;
+    writeln!(writer, "</Piece>\n</PolyData>\n</VTKFile>")
+}
+
+// Creates the file at `path` and writes it with `write`
+pub fn save(path: &str, write: impl FnOnce(&mut BufWriter<fs::File>) -> io::Result<()>) {
+    let file = fs::File::create(path).expect("Nie można utworzyć pliku");
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)
+        .and_then(|_| writer.flush())
+        .expect("Nie można zapisać do pliku");
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | ((byte as u32) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Snapshots of the simulation saved every few steps in headless mode: the
+// field on the grid (output/frames/grid_[n].vti) and the movable charges
+// (output/frames/charges_[n].vtp), listed with their times in the
+// output/grid.pvd and output/charges.pvd collections for ParaView.
+pub struct FrameSeries {
+    directory: String,
+    every: usize,
+    // whether the grid is saved too, it has to be populated
+    grid: bool,
+    steps: usize,
+    // (time [s], index) of the frames saved so far
+    frames: Vec<(f64, usize)>,
+}
+
+impl FrameSeries {
+    pub fn new(directory: &str, every: usize, grid: bool) -> Self {
+        fs::create_dir_all(format!("{}/frames", directory))
+            .expect("Nie można utworzyć katalogu na klatki");
+        FrameSeries {
+            directory: directory.to_string(),
+            every: every.max(1),
+            grid,
+            steps: 0,
+            frames: Vec::new(),
+        }
+    }
+
+    // Counts a step of the simulation, the frame is saved on every
+    // `every`-th step. The first frame should be saved before the first step.
+    pub fn step(&mut self, cellgrid: &mut CellGrid) {
+        self.steps += 1;
+        if self.steps.is_multiple_of(self.every) {
+            self.save_frame(cellgrid);
+        }
+    }
+
+    // saves the current state as the next frame and updates the collections
+    pub fn save_frame(&mut self, cellgrid: &mut CellGrid) {
+        let index = self.frames.len();
+        self.frames.push((cellgrid.time_elapsed, index));
+        if self.grid {
+            // the cells of time-varying charges are only updated on demand
+            cellgrid.refresh_outdated_field();
+            cellgrid.save_grid_frame(&format!("{}/frames/grid_{}.vti", self.directory, index));
+            self.save_collection("grid", "vti");
+        }
+        cellgrid.save_movable_charges_frame(&format!(
+            "{}/frames/charges_{}.vtp",
+            self.directory, index
+        ));
+        self.save_collection("charges", "vtp");
+    }
+
+    // the collection is rewritten with every frame, so it can be opened while
+    // the simulation is still running
+    fn save_collection(&self, name: &str, extension: &str) {
+        save(&format!("{}/{}.pvd", self.directory, name), |writer| {
+            xml_file(writer, "Collection")?;
+            writeln!(writer, "<Collection>")?;
+            for (t, index) in &self.frames {
+                writeln!(
+                    writer,
+                    "<DataSet timestep=\"{:e}\" group=\"\" part=\"0\" file=\"frames/{}_{}.{}\"/>",
+                    t, name, index, extension
+                )?;
+            }
+            writeln!(writer, "</Collection>\n</VTKFile>")
+        });
+    }
+}
@@ -0,0 +1,202 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+// Time dependence of a stationary charge, q(t) = q * f(t), where q is the
+// charge given in the scenario and f(t) the dimensionless waveform below. Times
+// are in seconds of the simulation time. In the scenario file the waveform is
+// the `przebieg` table of a charge with `typ` selecting the shape.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "typ", deny_unknown_fields)]
+pub enum Waveform {
+    // f(t) = offset + sin(2π f t + phase)
+    #[serde(rename = "sinus")]
+    Sine {
+        #[serde(rename = "czestotliwosc")]
+        frequency: f64,
+        #[serde(default, rename = "faza")]
+        phase: f64,
+        #[serde(default, rename = "skladowa_stala")]
+        offset: f64,
+    },
+    // f(t) = offset + 1 for the first `duty` fraction of every period and
+    // offset - 1 for the rest of it
+    #[serde(rename = "prostokatny")]
+    Square {
+        #[serde(rename = "czestotliwosc")]
+        frequency: f64,
+        #[serde(default, rename = "faza")]
+        phase: f64,
+        #[serde(default, rename = "skladowa_stala")]
+        offset: f64,
+        #[serde(default = "default_duty", rename = "wypelnienie")]
+        duty: f64,
+    },
+    // linear change from `from` at `start` to `to` at `end`, constant outside
+    #[serde(rename = "rampa")]
+    Ramp {
+        #[serde(rename = "od")]
+        from: f64,
+        #[serde(rename = "do")]
+        to: f64,
+        #[serde(default, rename = "poczatek")]
+        start: f64,
+        #[serde(rename = "koniec")]
+        end: f64,
+    },
+    // [t, f] points with increasing times, linear between them and constant
+    // before the first and after the last one
+    #[serde(rename = "tabela")]
+    Table {
+        #[serde(rename = "punkty")]
+        points: Vec<[f64; 2]>,
+    },
+}
+
+fn default_duty() -> f64 {
+    0.5
+}
+
+impl Waveform {
+    // the multiplier of the charge at time t [s]
+    pub fn value(&self, t: f64) -> f64 {
+        match *self {
+            Waveform::Sine {
+                frequency,
+                phase,
+                offset,
+            } => offset + (2. * PI * frequency * t + phase).sin(),
+            Waveform::Square {
+                frequency,
+                phase,
+                offset,
+                duty,
+            } => {
+                let fraction = (frequency * t + phase / (2. * PI)).rem_euclid(1.);
+                if fraction < duty {
+                    offset + 1.
+                } else {
+                    offset - 1.
+                }
+            }
+            Waveform::Ramp {
+                from,
+                to,
+                start,
+                end,
+            } => {
+                if t <= start {
+                    from
+                } else if t >= end {
+                    to
+                } else {
+                    from + (to - from) * (t - start) / (end - start)
+                }
+            }
+            Waveform::Table { ref points } => {
+                let (Some(first), Some(last)) = (points.first(), points.last()) else {
+                    return 1.;
+                };
+                if t <= first[0] {
+                    return first[1];
+                }
+                points
+                    .windows(2)
+                    .find(|pair| t < pair[1][0])
+                    .map_or(last[1], |pair| {
+                        let ([t0, f0], [t1, f1]) = (pair[0], pair[1]);
+                        f0 + (f1 - f0) * (t - t0) / (t1 - t0)
+                    })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn sine_values() {
+        let sine = Waveform::Sine {
+            frequency: 1e3,
+            phase: PI / 2.,
+            offset: 0.5,
+        };
+        assert_close(sine.value(0.), 1.5);
+        assert_close(sine.value(0.25e-3), 0.5);
+        assert_close(sine.value(0.5e-3), -0.5);
+        assert_close(sine.value(1e-3), 1.5);
+    }
+
+    #[test]
+    fn square_values() {
+        let square = Waveform::Square {
+            frequency: 10.,
+            phase: 0.,
+            offset: 0.,
+            duty: 0.25,
+        };
+        for (t, expected) in [(0., 1.), (0.02, 1.), (0.03, -1.), (0.09, -1.), (0.12, 1.)] {
+            assert_close(square.value(t), expected);
+        }
+        // half a period later with the phase of π, around the offset
+        let shifted = Waveform::Square {
+            frequency: 10.,
+            phase: PI,
+            offset: 2.,
+            duty: 0.5,
+        };
+        assert_close(shifted.value(0.01), 1.);
+        assert_close(shifted.value(0.06), 3.);
+    }
+
+    #[test]
+    fn ramp_values() {
+        let ramp = Waveform::Ramp {
+            from: 1.,
+            to: -3.,
+            start: 1.,
+            end: 3.,
+        };
+        for (t, expected) in [
+            (-5., 1.),
+            (1., 1.),
+            (1.5, 0.),
+            (2., -1.),
+            (3., -3.),
+            (10., -3.),
+        ] {
+            assert_close(ramp.value(t), expected);
+        }
+    }
+
+    #[test]
+    fn table_values() {
+        let table = Waveform::Table {
+            points: vec![[1., 2.], [2., 4.], [4., 0.]],
+        };
+        for (t, expected) in [
+            (0., 2.),
+            (1., 2.),
+            (1.5, 3.),
+            (2., 4.),
+            (3., 2.),
+            (4., 0.),
+            (9., 0.),
+        ] {
+            assert_close(table.value(t), expected);
+        }
+        // an empty table leaves the charge unchanged
+        assert_close(Waveform::Table { points: vec![] }.value(1.), 1.);
+    }
+}